@@ -0,0 +1,64 @@
+// Parsing an upstream answer, owned against the view.
+// Run with cargo bench --bench parse
+
+use std::hint::black_box;
+use std::net::Ipv4Addr;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use dnsproxy::{dns, message, view};
+use message::Message;
+
+// A CNAME and 8 addresses, like a CDN answer.
+fn answer() -> Vec<u8> {
+    let mut m = Message::new();
+    m.id = 7;
+    m.qr = 1;
+    m.rd = 1;
+    m.questions.push(message::Question{
+	name: "www.example.com.".to_owned(),
+	qtype: dns::RecordType::A,
+	class: dns::RecordClass::IN,
+    });
+    m.answers.push(dns::ResourceRecord{
+	name: "www.example.com.".to_owned(),
+	rtype: dns::RecordType::CNAME,
+	class: dns::RecordClass::IN,
+	ttl: 300,
+	data: dns::ResourceData::CName("cdn.example.net.".to_owned()),
+    });
+    for i in 0..8 {
+	m.answers.push(dns::ResourceRecord{
+	    name: "cdn.example.net.".to_owned(),
+	    rtype: dns::RecordType::A,
+	    class: dns::RecordClass::IN,
+	    ttl: 60,
+	    data: dns::ResourceData::IPv4(Ipv4Addr::new(192, 0, 2, i)),
+	});
+    }
+    m.edns = Some(message::Edns::new(1232));
+    m.into_bytes().unwrap()
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let data = answer();
+    c.bench_function("owned parse", |b| b.iter(|| {
+	Message::from(black_box(&data[..])).unwrap()
+    }));
+    c.bench_function("view records", |b| b.iter(|| {
+	let view = view::MessageView::new(black_box(&data[..])).unwrap();
+	view.records().map(|r| {
+	    let r = r.unwrap();
+	    r.name.labels().count() + r.rdata.len()
+	}).sum::<usize>()
+    }));
+    // What checking an upstream answer against the query reads.
+    c.bench_function("view question", |b| b.iter(|| {
+	let view = view::MessageView::new(black_box(&data[..])).unwrap();
+	let q = view.questions().next().unwrap().unwrap();
+	view.header().id == 7 && q.name.matches("www.example.com.", true)
+    }));
+}
+
+criterion_group!(benches, bench_parse);
+criterion_main!(benches);
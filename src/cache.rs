@@ -1,58 +1,275 @@
-use std::collections::HashMap;
-use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
 use std::time::{Instant, Duration};
 
+use crate::dns;
+use crate::FwdrAnswer;
+
+// RFC 2308 section 5 recommends capping negative caching at a few hours.
+const MAX_NEGATIVE_TTL: u32 = 3 * 3600;
+
+// Answers to queries with the DO bit carry DNSSEC records the others
+// don't, so the bit is part of the key.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CacheKey {
+    name: String,
+    qtype: u16,
+    class: u16,
+    dnssec_ok: bool,
+}
+
+impl CacheKey {
+    pub fn new(name: &str, qtype: dns::RecordType, class: dns::RecordClass,
+	       dnssec_ok: bool) -> CacheKey {
+	CacheKey{
+	    name: name.to_lowercase(),
+	    qtype: qtype.into(),
+	    class: class.into(),
+	    dnssec_ok,
+	}
+    }
+}
+
 #[derive(Debug)]
 pub struct CacheEntry {
-    a: Ipv4Addr,
+    answer: FwdrAnswer,
+    inserted: Instant,
     expiry: Instant,
 }
 
 impl CacheEntry {
-    pub fn new(a: &Ipv4Addr, ttl: u64) -> CacheEntry {
+    pub fn new(answer: &FwdrAnswer, ttl: u64) -> CacheEntry {
+	let now = Instant::now();
 	CacheEntry{
-	    a: a.clone(),
-	    expiry: Instant::now() + Duration::from_secs(ttl),
+	    answer: answer.clone(),
+	    inserted: now,
+	    expiry: now + Duration::from_secs(ttl),
 	}
     }
-    pub fn get_ttl(&self) -> u64 {
-	return (self.expiry - Instant::now()).as_secs();
-    }
     pub fn is_valid(&self) -> bool {
-	return self.expiry > Instant::now();
+	self.expiry > Instant::now()
+    }
+
+    // Returns the answer with every TTL reduced by the time spent in the cache.
+    fn answer(&self) -> FwdrAnswer {
+	let elapsed = self.inserted.elapsed().as_secs().min(u32::MAX as u64) as u32;
+	let mut answer = self.answer.clone();
+	for r in answer.answers.iter_mut()
+	    .chain(answer.nameservers.iter_mut())
+	    .chain(answer.additional.iter_mut()) {
+	    r.ttl = r.ttl.saturating_sub(elapsed);
+	}
+	answer
     }
 }
 
 #[derive(Debug)]
-struct Cache {
-    table: HashMap<String, CacheEntry>,
+pub struct Cache {
+    table: HashMap<CacheKey, CacheEntry>,
+    // The table's keys ordered by expiry, for eviction.
+    by_expiry: BTreeSet<(Instant, CacheKey)>,
+    capacity: usize,
 }
 
 impl Cache {
-    pub fn new() -> Cache {
-	Cache{table: HashMap::new()}
+    pub fn new(capacity: usize) -> Cache {
+	Cache{table: HashMap::new(), by_expiry: BTreeSet::new(), capacity}
+    }
+
+    // Caches an answer for as long as its shortest TTL. NXDOMAIN and NODATA
+    // answers are only cached when they carry the zone's SOA.
+    pub fn insert(&mut self, key: CacheKey, answer: &FwdrAnswer) {
+	if self.capacity == 0 {
+	    return;
+	}
+	let negative = match answer.rcode {
+	    0 => answer.answers.is_empty(), // NODATA
+	    3 => true, // NXDOMAIN
+	    _ => return,
+	};
+	let answer = if negative {
+	    match negative_answer(answer) {
+		Some(a) => a,
+		None => return,
+	    }
+	} else {
+	    answer.clone()
+	};
+	let ttl = answer.answers.iter()
+	    .chain(answer.nameservers.iter())
+	    .chain(answer.additional.iter())
+	    .map(|r| r.ttl)
+	    .min()
+	    .unwrap_or(0);
+	if ttl == 0 {
+	    return;
+	}
+	if self.table.len() >= self.capacity && !self.table.contains_key(&key) {
+	    self.evict();
+	}
+	let entry = CacheEntry::new(&answer, ttl as u64);
+	self.by_expiry.insert((entry.expiry, key.clone()));
+	if let Some(old) = self.table.insert(key.clone(), entry) {
+	    self.by_expiry.remove(&(old.expiry, key));
+	}
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+	if let Some(entry) = self.table.remove(key) {
+	    self.by_expiry.remove(&(entry.expiry, key.clone()));
+	}
     }
 
-    pub fn insert(&mut self, name: &str, a: &Ipv4Addr, ttl: u64) {
-	self.table.insert(name.to_lowercase().to_owned(),
-			  CacheEntry::new(a, ttl));
+    pub fn set_capacity(&mut self, capacity: usize) {
+	self.capacity = capacity;
+	while self.table.len() > capacity {
+	    self.evict();
+	}
     }
 
-    fn get_(&mut self, name: &str) -> Option<&CacheEntry> {
-	match self.table.get(&name.to_lowercase()) {
-	    Some(entry) => Some(entry),
-	    None => None,
+    // Drops the entry closest to expiry and any others already expired.
+    fn evict(&mut self) {
+	let now = Instant::now();
+	let mut first = true;
+	while let Some((expiry, key)) = self.by_expiry.first().cloned() {
+	    if !first && expiry > now {
+		break;
+	    }
+	    first = false;
+	    self.remove(&key);
 	}
     }
 
-    pub fn get(&mut self, name: &str) -> Option<(Ipv4Addr, u64)> {
-	if let Entry::Occupied(e) = self.table.entry(name.to_lowercase()) {
-            if !e.get().is_valid() {
-		e.remove_entry();
-		return None;
-            }
-	    return Some((e.get().a.clone(), e.get().get_ttl()));
+    pub fn get(&mut self, key: &CacheKey) -> Option<FwdrAnswer> {
+	let entry = self.table.get(key)?;
+	if !entry.is_valid() {
+	    self.remove(key);
+	    return None;
 	}
-	return None;
+	Some(entry.answer())
+    }
+}
+
+// Reduces a negative answer to what RFC 2308 says to cache: the answer
+// section (a possible CNAME chain) and the SOA from the authority section,
+// whose TTL becomes the negative TTL, min(SOA TTL, SOA minimum).
+fn negative_answer(answer: &FwdrAnswer) -> Option<FwdrAnswer> {
+    let mut soa = answer.nameservers.iter()
+	.find(|r| matches!(r.data, dns::ResourceData::Soa(_)))?
+	.clone();
+    if let dns::ResourceData::Soa(data) = &soa.data {
+	soa.ttl = soa.ttl.min(data.minimum).min(MAX_NEGATIVE_TTL);
+    }
+    Some(FwdrAnswer{
+	rcode: answer.rcode,
+	answers: answer.answers.clone(),
+	nameservers: vec![soa],
+	additional: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn answer(ttls: &[u32]) -> FwdrAnswer {
+	FwdrAnswer{
+	    rcode: 0,
+	    answers: ttls.iter().map(|ttl| dns::ResourceRecord{
+		name: "example.com.".to_owned(),
+		rtype: dns::RecordType::A,
+		class: dns::RecordClass::IN,
+		ttl: *ttl,
+		data: dns::ResourceData::IPv4(Ipv4Addr::new(1, 2, 3, 4)),
+	    }).collect(),
+	    nameservers: Vec::new(),
+	    additional: Vec::new(),
+	}
+    }
+
+    fn negative(rcode: u32, soa_ttl: u32, minimum: u32) -> FwdrAnswer {
+	let mut a = answer(&[]);
+	a.rcode = rcode;
+	a.nameservers.push(dns::ResourceRecord{
+	    name: "com.".to_owned(),
+	    rtype: dns::RecordType::SOA,
+	    class: dns::RecordClass::IN,
+	    ttl: soa_ttl,
+	    data: dns::ResourceData::Soa(dns::Soa{
+		mname: "a.gtld-servers.net.".to_owned(),
+		rname: "nstld.verisign-grs.com.".to_owned(),
+		serial: 1,
+		refresh: 1800,
+		retry: 900,
+		expire: 604800,
+		minimum,
+	    }),
+	});
+	a.additional = answer(&[300]).answers;
+	a
+    }
+
+    fn key(name: &str) -> CacheKey {
+	CacheKey::new(name, dns::RecordType::A, dns::RecordClass::IN, false)
+    }
+
+    #[test]
+    fn test_insert_get() {
+	let mut cache = Cache::new(16);
+	cache.insert(key("Example.COM."), &answer(&[300, 60]));
+	let a = cache.get(&key("example.com.")).unwrap();
+	assert_eq!(a.answers.len(), 2);
+	assert_eq!(a.answers[0].ttl, 300);
+	assert!(cache.table[&key("example.com.")].expiry <= Instant::now() + Duration::from_secs(60));
+	assert!(cache.get(&CacheKey::new("example.com.", dns::RecordType::AAAA, dns::RecordClass::IN, false)).is_none());
+	assert!(cache.get(&CacheKey::new("example.com.", dns::RecordType::A, dns::RecordClass::IN, true)).is_none());
+	cache.insert(key("example.com."), &answer(&[120]));
+	assert_eq!(cache.by_expiry.len(), 1);
+    }
+
+    #[test]
+    fn test_not_cached() {
+	let mut cache = Cache::new(16);
+	cache.insert(key("zero.com."), &answer(&[0]));
+	cache.insert(key("empty.com."), &answer(&[]));
+	cache.insert(key("nosoa.com."), &FwdrAnswer{rcode: 3, ..answer(&[])});
+	let mut servfail = answer(&[300]);
+	servfail.rcode = 2;
+	cache.insert(key("servfail.com."), &servfail);
+	assert!(cache.table.is_empty());
+    }
+
+    #[test]
+    fn test_negative() {
+	let mut cache = Cache::new(16);
+	cache.insert(key("nx.com."), &negative(3, 900, 60));
+	cache.insert(key("nodata.com."), &negative(0, 30, 60));
+	let nx = cache.get(&key("nx.com.")).unwrap();
+	assert_eq!(nx.rcode, 3);
+	assert!(nx.answers.is_empty());
+	assert!(nx.additional.is_empty());
+	assert_eq!(nx.nameservers.len(), 1);
+	assert_eq!(nx.nameservers[0].ttl, 60);
+	let nodata = cache.get(&key("nodata.com.")).unwrap();
+	assert_eq!(nodata.rcode, 0);
+	assert_eq!(nodata.nameservers[0].ttl, 30);
+	cache.insert(key("long.com."), &negative(3, 86400, 86400));
+	assert_eq!(cache.get(&key("long.com.")).unwrap().nameservers[0].ttl, MAX_NEGATIVE_TTL);
+    }
+
+    #[test]
+    fn test_capacity() {
+	let mut cache = Cache::new(2);
+	cache.insert(key("a.com."), &answer(&[10]));
+	cache.insert(key("b.com."), &answer(&[300]));
+	cache.insert(key("c.com."), &answer(&[300]));
+	assert_eq!(cache.table.len(), 2);
+	assert!(cache.get(&key("a.com.")).is_none());
+	assert!(cache.get(&key("c.com.")).is_some());
+	cache.set_capacity(1);
+	assert_eq!(cache.table.len(), 1);
+	cache.set_capacity(0);
+	assert!(cache.table.is_empty());
+	assert!(cache.by_expiry.is_empty());
     }
 }
@@ -0,0 +1,59 @@
+use crate::config::ChaosConfig;
+use crate::dns;
+
+// Answers to CHAOS class TXT queries identifying the server, RFC 4892.
+// These describe the proxy itself and are never forwarded.
+
+fn record(name: &str, value: &str) -> dns::ResourceRecord {
+    dns::ResourceRecord{
+	name: name.to_owned(),
+	rtype: dns::RecordType::TXT,
+	class: dns::RecordClass::CH,
+	ttl: 0,
+	data: dns::ResourceData::Txt(value.as_bytes().chunks(255).map(|c| c.to_vec()).collect()),
+    }
+}
+
+// Returns the answers for the question, empty for other types than TXT,
+// or None if the name is unknown or its value isn't configured.
+pub fn lookup(config: &ChaosConfig, name: &str, qtype: dns::RecordType) -> Option<Vec<dns::ResourceRecord>> {
+    let value = match name.to_ascii_lowercase().as_str() {
+	"version.bind." => &config.version,
+	"hostname.bind." => &config.hostname,
+	"id.server." => &config.id,
+	_ => return None,
+    };
+    if value.is_empty() {
+	return None;
+    }
+    match u16::from(qtype) {
+	16 | 255 => Some(vec![record(name, value)]), // TXT or ANY
+	_ => Some(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+	let config = ChaosConfig{
+	    version: "dnsproxy 1.0".to_owned(),
+	    hostname: String::new(),
+	    id: "x".repeat(300),
+	};
+	let answers = lookup(&config, "VERSION.bind.", dns::RecordType::TXT).unwrap();
+	assert_eq!(answers[0].name, "VERSION.bind.");
+	assert_eq!(answers[0].data.to_string(), "\"dnsproxy 1.0\"");
+	assert!(lookup(&config, "version.bind.", dns::RecordType::A).unwrap().is_empty());
+	assert!(lookup(&config, "hostname.bind.", dns::RecordType::TXT).is_none());
+	assert!(lookup(&config, "authors.bind.", dns::RecordType::TXT).is_none());
+	match &lookup(&config, "id.server.", dns::RecordType::TXT).unwrap()[0].data {
+	    dns::ResourceData::Txt(strings) => assert_eq!(strings.len(), 2),
+	    d => panic!("{:?}", d),
+	}
+	let id = &lookup(&config, "id.server.", dns::RecordType::TXT).unwrap()[0].data;
+	assert_eq!(id.txt_lossy().unwrap(), config.id);
+    }
+}
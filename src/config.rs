@@ -0,0 +1,472 @@
+use clap::Parser;
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::tls;
+use crate::upstream;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	match self {
+	    ConfigError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+	    ConfigError::Parse(path, e) => write!(f, "{}: {}", path.display(), e),
+	    ConfigError::Invalid(msg) => write!(f, "invalid configuration: {}", msg),
+	}
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Parser, Debug, Clone)]
+#[command(name = "dnsproxy", about = "DNS forwarding proxy with DoH support")]
+pub struct Args {
+    /// Path to the TOML configuration file
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// UDP listen address
+    #[arg(long)]
+    pub udp: Option<SocketAddr>,
+    /// TCP listen address
+    #[arg(long)]
+    pub tcp: Option<SocketAddr>,
+    /// DoH listen address
+    #[arg(long)]
+    pub doh: Option<SocketAddr>,
+    /// DoT listen address, needs --tls-cert and --tls-key
+    #[arg(long)]
+    pub dot: Option<SocketAddr>,
+    /// PEM certificate chain for DoH and DoT, enables TLS for DoH
+    #[arg(long)]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[arg(long)]
+    pub tls_key: Option<PathBuf>,
+    /// Upstream server: address, tls://host[:port] or https:// URL.
+    /// May be repeated (replaces the configured list)
+    #[arg(short, long = "upstream")]
+    pub upstreams: Vec<String>,
+    /// Upstream selection strategy: round-robin, random, lowest-latency or race
+    #[arg(long)]
+    pub strategy: Option<String>,
+    /// Upstream query timeout in milliseconds
+    #[arg(long)]
+    pub timeout_ms: Option<u64>,
+    /// Hosts file to load
+    #[arg(long)]
+    pub hosts: Option<PathBuf>,
+    /// Maximum number of cached responses
+    #[arg(long)]
+    pub cache_size: Option<usize>,
+    /// Maximum number of UDP questions resolved at once
+    #[arg(long)]
+    pub max_inflight: Option<usize>,
+    /// Maximum number of UDP questions resolved at once for one client
+    #[arg(long)]
+    pub max_inflight_per_client: Option<usize>,
+    /// Log level: off, error, warn, info, debug or trace
+    #[arg(short, long)]
+    pub log_level: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log_level: String,
+    pub hosts: Option<PathBuf>,
+    pub listen: ListenConfig,
+    pub tls: TlsConfig,
+    pub upstream: UpstreamConfig,
+    pub cache: CacheConfig,
+    pub limits: LimitsConfig,
+    pub chaos: ChaosConfig,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    pub udp: SocketAddr,
+    pub tcp: SocketAddr,
+    pub doh: SocketAddr,
+    pub dot: Option<SocketAddr>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+// An upstream is either just its address or URL, or a table adding TLS
+// options.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum UpstreamServer {
+    Url(String),
+    Detailed(UpstreamOptions),
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamOptions {
+    pub url: String,
+    #[serde(default)]
+    pub server_name: Option<String>,
+    // Base64 SHA-256 digests of acceptable server public keys.
+    #[serde(default)]
+    pub spki_pins: Vec<String>,
+    // Overrides upstream.randomize_case for this server.
+    #[serde(default)]
+    pub randomize_case: Option<bool>,
+    // Address of an https:// upstream given by host name.
+    #[serde(default)]
+    pub addr: Option<IpAddr>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    pub servers: Vec<UpstreamServer>,
+    pub strategy: String,
+    pub timeout_ms: u64,
+    // Upstreams queried at once with the race strategy.
+    pub race_width: usize,
+    // DNS 0x20, turn off for upstreams that don't echo the name's case.
+    pub randomize_case: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub size: usize,
+}
+
+// Answers to CHAOS class TXT queries about the server, RFC 4892. An empty
+// value refuses the query.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChaosConfig {
+    // version.bind
+    pub version: String,
+    // hostname.bind
+    pub hostname: String,
+    // id.server
+    pub id: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_inflight: usize,
+    pub max_inflight_per_client: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+	Config{
+	    log_level: "info".to_owned(),
+	    hosts: None,
+	    listen: ListenConfig::default(),
+	    tls: TlsConfig::default(),
+	    upstream: UpstreamConfig::default(),
+	    cache: CacheConfig::default(),
+	    limits: LimitsConfig::default(),
+	    chaos: ChaosConfig::default(),
+	}
+    }
+}
+
+impl Default for ListenConfig {
+    fn default() -> ListenConfig {
+	ListenConfig{
+	    udp: SocketAddr::from(([0, 0, 0, 0], 3553)),
+	    tcp: SocketAddr::from(([0, 0, 0, 0], 3553)),
+	    doh: SocketAddr::from(([127, 0, 0, 1], 4443)),
+	    dot: None,
+	}
+    }
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> UpstreamConfig {
+	UpstreamConfig{
+	    servers: vec![UpstreamServer::Url("9.9.9.9".to_owned())],
+	    strategy: "round-robin".to_owned(),
+	    timeout_ms: 2000,
+	    race_width: 2,
+	    randomize_case: true,
+	}
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+	CacheConfig{
+	    size: 4096,
+	}
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> LimitsConfig {
+	LimitsConfig{
+	    max_inflight: 1024,
+	    max_inflight_per_client: 64,
+	}
+    }
+}
+
+impl Default for ChaosConfig {
+    fn default() -> ChaosConfig {
+	let hostname = hostname().unwrap_or_default();
+	ChaosConfig{
+	    version: format!("dnsproxy {}", env!("CARGO_PKG_VERSION")),
+	    id: hostname.clone(),
+	    hostname,
+	}
+    }
+}
+
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+	return None;
+    }
+    let len = buf.iter().position(|b| *b == 0)?;
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+impl UpstreamServer {
+    fn upstream(&self, randomize_case: bool) -> Result<upstream::Upstream, std::io::Error> {
+	let mut upstream = match self {
+	    UpstreamServer::Url(url) => upstream::Upstream::parse(url, None, &[], None)?,
+	    UpstreamServer::Detailed(o) => {
+		let pins = o.spki_pins.iter()
+		    .map(|p| tls::parse_pin(p))
+		    .collect::<Result<Vec<_>, _>>()?;
+		upstream::Upstream::parse(&o.url, o.server_name.as_deref(), &pins, o.addr)?
+	    },
+	};
+	upstream.randomize_case = match self {
+	    UpstreamServer::Detailed(o) => o.randomize_case.unwrap_or(randomize_case),
+	    UpstreamServer::Url(_) => randomize_case,
+	};
+	Ok(upstream)
+    }
+}
+
+impl UpstreamConfig {
+    pub fn timeout(&self) -> Duration {
+	Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn pool(&self) -> Result<upstream::UpstreamPool, ConfigError> {
+	let mut upstreams = Vec::new();
+	for (i, s) in self.servers.iter().enumerate() {
+	    let upstream = s.upstream(self.randomize_case)
+		.map_err(|e| ConfigError::Invalid(format!("upstream.servers[{}]: {}", i, e)))?;
+	    upstreams.push(upstream);
+	}
+	if upstreams.is_empty() {
+	    return Err(ConfigError::Invalid("upstream.servers is empty".to_owned()));
+	}
+	let strategy = upstream::Strategy::from_str(&self.strategy)
+	    .map_err(|e| ConfigError::Invalid(format!("upstream.strategy: {}", e)))?;
+	let mut pool = upstream::UpstreamPool::new(upstreams, strategy, self.timeout());
+	pool.set_race_width(self.race_width);
+	Ok(pool)
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+	let data = std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+	toml::from_str(&data).map_err(|e| ConfigError::Parse(path.to_owned(), e))
+    }
+
+    // Loads the configuration file, if any, applies the command line
+    // overrides on top and validates the result.
+    pub fn from_args(args: Args) -> Result<Config, ConfigError> {
+	let mut config = match &args.config {
+	    Some(path) => Config::load(path)?,
+	    None => Config::default(),
+	};
+	if let Some(udp) = args.udp {
+	    config.listen.udp = udp;
+	}
+	if let Some(tcp) = args.tcp {
+	    config.listen.tcp = tcp;
+	}
+	if let Some(doh) = args.doh {
+	    config.listen.doh = doh;
+	}
+	if let Some(dot) = args.dot {
+	    config.listen.dot = Some(dot);
+	}
+	if let Some(cert) = args.tls_cert {
+	    config.tls.cert = Some(cert);
+	}
+	if let Some(key) = args.tls_key {
+	    config.tls.key = Some(key);
+	}
+	if !args.upstreams.is_empty() {
+	    config.upstream.servers = args.upstreams.into_iter().map(UpstreamServer::Url).collect();
+	}
+	if let Some(strategy) = args.strategy {
+	    config.upstream.strategy = strategy;
+	}
+	if let Some(timeout_ms) = args.timeout_ms {
+	    config.upstream.timeout_ms = timeout_ms;
+	}
+	if let Some(hosts) = args.hosts {
+	    config.hosts = Some(hosts);
+	}
+	if let Some(size) = args.cache_size {
+	    config.cache.size = size;
+	}
+	if let Some(n) = args.max_inflight {
+	    config.limits.max_inflight = n;
+	}
+	if let Some(n) = args.max_inflight_per_client {
+	    config.limits.max_inflight_per_client = n;
+	}
+	if let Some(level) = args.log_level {
+	    config.log_level = level;
+	}
+	config.validate()?;
+	Ok(config)
+    }
+
+    pub fn log_level(&self) -> log::LevelFilter {
+	log::LevelFilter::from_str(&self.log_level).unwrap_or(log::LevelFilter::Info)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+	if log::LevelFilter::from_str(&self.log_level).is_err() {
+	    return Err(ConfigError::Invalid(format!("log_level: unknown level {:?}", self.log_level)));
+	}
+	if self.upstream.timeout_ms == 0 {
+	    return Err(ConfigError::Invalid("upstream.timeout_ms must be greater than 0".to_owned()));
+	}
+	if self.upstream.race_width == 0 {
+	    return Err(ConfigError::Invalid("upstream.race_width must be greater than 0".to_owned()));
+	}
+	self.upstream.pool()?;
+	if self.limits.max_inflight == 0 || self.limits.max_inflight_per_client == 0 {
+	    return Err(ConfigError::Invalid("limits must be greater than 0".to_owned()));
+	}
+	match (&self.tls.cert, &self.tls.key) {
+	    (Some(_), None) | (None, Some(_)) => {
+		return Err(ConfigError::Invalid("tls.cert and tls.key must be set together".to_owned()));
+	    },
+	    _ => (),
+	}
+	if self.listen.dot.is_some() && self.tls.cert.is_none() {
+	    return Err(ConfigError::Invalid("listen.dot needs tls.cert and tls.key".to_owned()));
+	}
+	if let Some(hosts) = &self.hosts {
+	    if !hosts.is_file() {
+		return Err(ConfigError::Invalid(format!("hosts: {} is not a file", hosts.display())));
+	    }
+	}
+	Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<Config, ConfigError> {
+	let config: Config = toml::from_str(s).map_err(|e| ConfigError::Parse(PathBuf::from("test"), e))?;
+	config.validate()?;
+	Ok(config)
+    }
+
+    #[test]
+    fn test_defaults() {
+	let config = parse("").unwrap();
+	assert_eq!(config.listen.udp, "0.0.0.0:3553".parse().unwrap());
+	assert_eq!(config.listen.tcp, "0.0.0.0:3553".parse().unwrap());
+	assert_eq!(config.listen.doh, "127.0.0.1:4443".parse().unwrap());
+	assert_eq!(config.upstream.servers, vec![UpstreamServer::Url("9.9.9.9".to_owned())]);
+	assert_eq!(config.log_level(), log::LevelFilter::Info);
+    }
+
+    #[test]
+    fn test_full() {
+	let config = parse(r#"
+log_level = "debug"
+
+[listen]
+udp = "127.0.0.1:53"
+
+[upstream]
+servers = [
+    "1.1.1.1",
+    "[2606:4700:4700::1111]:53",
+    { url = "tls://1.1.1.1", server_name = "one.one.one.one", spki_pins = ["eXXWmqmKvRnzuq1IeGZiBAwZ3XeyXdtGxjEoejD+uX0="] },
+    { url = "1.0.0.1", randomize_case = true },
+    { url = "https://dns.quad9.net/dns-query", addr = "9.9.9.9" },
+]
+strategy = "lowest-latency"
+timeout_ms = 500
+randomize_case = false
+
+[cache]
+size = 10
+
+[limits]
+max_inflight_per_client = 8
+
+[chaos]
+version = ""
+id = "pop1"
+"#).unwrap();
+	assert_eq!(config.listen.udp, "127.0.0.1:53".parse().unwrap());
+	assert_eq!(config.upstream.timeout(), Duration::from_millis(500));
+	assert!(matches!(&config.upstream.servers[2], UpstreamServer::Detailed(o) if o.spki_pins.len() == 1));
+	let pool = config.upstream.pool().unwrap();
+	assert!(!pool.get(0).randomize_case);
+	assert!(pool.get(3).randomize_case);
+	assert_eq!(config.cache.size, 10);
+	assert_eq!(config.limits.max_inflight, 1024);
+	assert_eq!(config.limits.max_inflight_per_client, 8);
+	assert_eq!(config.log_level(), log::LevelFilter::Debug);
+	assert_eq!(config.chaos.version, "");
+	assert_eq!(config.chaos.hostname, ChaosConfig::default().hostname);
+	assert_eq!(config.chaos.id, "pop1");
+    }
+
+    #[test]
+    fn test_invalid() {
+	assert!(matches!(parse("[listen]\nudp = \"nope\""), Err(ConfigError::Parse(..))));
+	assert!(matches!(parse("bogus = 1"), Err(ConfigError::Parse(..))));
+	assert!(matches!(parse("log_level = \"loud\""), Err(ConfigError::Invalid(..))));
+	assert!(matches!(parse("[upstream]\nservers = []"), Err(ConfigError::Invalid(..))));
+	assert!(matches!(parse("[upstream]\nservers = [\"x\"]"), Err(ConfigError::Invalid(..))));
+	assert!(matches!(parse("[upstream]\nservers = [{ url = \"tls://1.1.1.1\", spki_pins = [\"x\"] }]"),
+			 Err(ConfigError::Invalid(..))));
+	assert!(matches!(parse("[upstream]\nservers = [{ url = \"tls://1.1.1.1\", pin = \"x\" }]"),
+			 Err(ConfigError::Parse(..))));
+	assert!(matches!(parse("[upstream]\nservers = [\"https://dns.quad9.net/dns-query\"]"),
+			 Err(ConfigError::Invalid(..))));
+	assert!(matches!(parse("[upstream]\nstrategy = \"fastest\""), Err(ConfigError::Invalid(..))));
+	assert!(matches!(parse("[upstream]\nstrategy = \"race\"\nrace_width = 0"), Err(ConfigError::Invalid(..))));
+	assert!(matches!(parse("[upstream]\ntimeout_ms = 0"), Err(ConfigError::Invalid(..))));
+	assert!(matches!(parse("[limits]\nmax_inflight = 0"), Err(ConfigError::Invalid(..))));
+	assert!(matches!(parse("[tls]\ncert = \"cert.pem\""), Err(ConfigError::Invalid(..))));
+	assert!(matches!(parse("[listen]\ndot = \"0.0.0.0:853\""), Err(ConfigError::Invalid(..))));
+    }
+}
@@ -1,34 +1,89 @@
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
 
+use crate::message::MessageError;
+
 #[derive(Debug, Clone, Copy)]
 pub enum RecordType {
     A,
     NS,
+    MD,
+    MF,
     CNAME,
     SOA,
+    MB,
+    MG,
+    MR,
     PTR,
+    MINFO,
     MX,
     TXT,
+    RP,
+    AFSDB,
+    RT,
     AAAA,
+    SRV,
+    NAPTR,
+    OPT,
+    DS,
+    SSHFP,
+    RRSIG,
+    NSEC,
+    DNSKEY,
+    NSEC3,
+    TLSA,
+    SVCB,
     HTTPS,
+    CAA,
     UNKNOWN(u16),
 }
 
+// Types with a mnemonic, for parsing and printing them by name.
+const NAMED_TYPES: [RecordType; 30] = [
+    RecordType::A, RecordType::NS, RecordType::MD, RecordType::MF, RecordType::CNAME,
+    RecordType::SOA, RecordType::MB, RecordType::MG, RecordType::MR, RecordType::PTR,
+    RecordType::MINFO, RecordType::MX, RecordType::TXT, RecordType::RP, RecordType::AFSDB,
+    RecordType::RT, RecordType::AAAA, RecordType::SRV, RecordType::NAPTR,
+    RecordType::OPT, RecordType::DS, RecordType::SSHFP, RecordType::RRSIG, RecordType::NSEC,
+    RecordType::DNSKEY, RecordType::NSEC3, RecordType::TLSA, RecordType::SVCB, RecordType::HTTPS,
+    RecordType::CAA,
+];
+
 impl TryFrom<u16> for RecordType {
-    type Error = std::io::Error;
+    type Error = MessageError;
 
     fn try_from(value: u16) -> Result<RecordType, Self::Error> {
 	match value {
 	    1  => Ok(RecordType::A),
 	    2  => Ok(RecordType::NS),
+	    3  => Ok(RecordType::MD),
+	    4  => Ok(RecordType::MF),
 	    5  => Ok(RecordType::CNAME),
 	    6  => Ok(RecordType::SOA),
+	    7  => Ok(RecordType::MB),
+	    8  => Ok(RecordType::MG),
+	    9  => Ok(RecordType::MR),
 	    12 => Ok(RecordType::PTR),
+	    14 => Ok(RecordType::MINFO),
 	    15 => Ok(RecordType::MX),
 	    16 => Ok(RecordType::TXT),
+	    17 => Ok(RecordType::RP),
+	    18 => Ok(RecordType::AFSDB),
+	    21 => Ok(RecordType::RT),
 	    28 => Ok(RecordType::AAAA),
+	    33 => Ok(RecordType::SRV),
+	    35 => Ok(RecordType::NAPTR),
+	    41 => Ok(RecordType::OPT),
+	    43 => Ok(RecordType::DS),
+	    44 => Ok(RecordType::SSHFP),
+	    46 => Ok(RecordType::RRSIG),
+	    47 => Ok(RecordType::NSEC),
+	    48 => Ok(RecordType::DNSKEY),
+	    50 => Ok(RecordType::NSEC3),
+	    52 => Ok(RecordType::TLSA),
+	    64 => Ok(RecordType::SVCB),
 	    65 => Ok(RecordType::HTTPS),
+	    257 => Ok(RecordType::CAA),
 	    rt => Ok(RecordType::UNKNOWN(rt)),
 	}
     }
@@ -39,13 +94,34 @@ impl From<RecordType> for u16 {
 	match rtype {
 	    RecordType::A => 1,
 	    RecordType::NS => 2,	
+	    RecordType::MD => 3,
+	    RecordType::MF => 4,
 	    RecordType::CNAME => 5,
 	    RecordType::SOA => 6,	
+	    RecordType::MB => 7,
+	    RecordType::MG => 8,
+	    RecordType::MR => 9,
 	    RecordType::PTR => 12,
+	    RecordType::MINFO => 14,
 	    RecordType::MX => 15,
 	    RecordType::TXT => 16,
+	    RecordType::RP => 17,
+	    RecordType::AFSDB => 18,
+	    RecordType::RT => 21,
 	    RecordType::AAAA => 28,
+	    RecordType::SRV => 33,
+	    RecordType::NAPTR => 35,
+	    RecordType::OPT => 41,
+	    RecordType::DS => 43,
+	    RecordType::SSHFP => 44,
+	    RecordType::RRSIG => 46,
+	    RecordType::NSEC => 47,
+	    RecordType::DNSKEY => 48,
+	    RecordType::NSEC3 => 50,
+	    RecordType::TLSA => 52,
+	    RecordType::SVCB => 64,
 	    RecordType::HTTPS => 65,
+	    RecordType::CAA => 257,
 	    RecordType::UNKNOWN(rt) => rt,
 	}
     }
@@ -54,15 +130,24 @@ impl From<RecordType> for u16 {
 #[derive(Debug, Clone, Copy)]
 pub enum RecordClass {
     IN,
+    CH,
+    HS,
+    NONE,
+    ANY,
+    UNKNOWN(u16),
 }
 
 impl TryFrom<u16> for RecordClass {
-    type Error = std::io::Error;
+    type Error = MessageError;
 
     fn try_from(value: u16) -> Result<RecordClass, Self::Error> {
 	match value {
-	    1  => Ok(RecordClass::IN),
-	    _  => Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Unsupported RecordClass {:?}", value))),
+	    1   => Ok(RecordClass::IN),
+	    3   => Ok(RecordClass::CH),
+	    4   => Ok(RecordClass::HS),
+	    254 => Ok(RecordClass::NONE),
+	    255 => Ok(RecordClass::ANY),
+	    c   => Ok(RecordClass::UNKNOWN(c)),
 	}
     }
 }
@@ -71,6 +156,11 @@ impl From<RecordClass> for u16 {
     fn from(class: RecordClass) -> u16 {
 	match class {
 	    RecordClass::IN => 1,
+	    RecordClass::CH => 3,
+	    RecordClass::HS => 4,
+	    RecordClass::NONE => 254,
+	    RecordClass::ANY => 255,
+	    RecordClass::UNKNOWN(c) => c,
 	}
     }
 }
@@ -93,9 +183,25 @@ pub enum ResourceData {
     Soa(Soa),
     Ptr(String),
     Mx(Mx),
-    Txt(String),
+    // The character-strings as sent, one record may hold several of up
+    // to 255 bytes each.
+    Txt(Vec<Vec<u8>>),
+    Srv(Srv),
+    Naptr(Naptr),
+    Ds(Ds),
+    Sshfp(Sshfp),
+    Rrsig(Rrsig),
+    Nsec(Nsec),
+    Dnskey(Dnskey),
+    Nsec3(Nsec3),
+    Tlsa(Tlsa),
+    Svcb(Svcb),
     Https(Svcb),
-    Unimplemented(u32),
+    Caa(Caa),
+    // MD, MF, MB, MG, MR, MINFO, RP, AFSDB and RT.
+    Names(Names),
+    // RDATA of types without a parser, kept as is, RFC 3597.
+    Unknown(Vec<u8>),
 }
 
 #[derive(Debug, Clone)]
@@ -115,8 +221,113 @@ pub struct Mx {
     pub exchange: String,
 }
 
+// Older types holding only domain names, after a 16 bit preference or
+// subtype for AFSDB and RT (RFC 1035, RFC 1183). Their names may be
+// compressed, so unlike other unknown types they can't be copied as is.
+#[derive(Debug, Clone)]
+pub struct Names {
+    pub number: Option<u16>,
+    pub names: Vec<String>,
+}
+
+// RFC 2782
+#[derive(Debug, Clone)]
+pub struct Srv {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+// RFC 3403
+#[derive(Debug, Clone)]
+pub struct Naptr {
+    pub order: u16,
+    pub preference: u16,
+    pub flags: Vec<u8>,
+    pub services: Vec<u8>,
+    pub regexp: Vec<u8>,
+    pub replacement: String,
+}
+
+// RFC 4034 section 5
+#[derive(Debug, Clone)]
+pub struct Ds {
+    pub key_tag: u16,
+    pub algorithm: u8,
+    pub digest_type: u8,
+    pub digest: Vec<u8>,
+}
+
+// RFC 4255
+#[derive(Debug, Clone)]
+pub struct Sshfp {
+    pub algorithm: u8,
+    pub fp_type: u8,
+    pub fingerprint: Vec<u8>,
+}
+
+// RFC 4034 section 3
+#[derive(Debug, Clone)]
+pub struct Rrsig {
+    pub type_covered: RecordType,
+    pub algorithm: u8,
+    pub labels: u8,
+    pub original_ttl: u32,
+    pub expiration: u32,
+    pub inception: u32,
+    pub key_tag: u16,
+    pub signer_name: String,
+    pub signature: Vec<u8>,
+}
+
+// RFC 4034 section 4
+#[derive(Debug, Clone)]
+pub struct Nsec {
+    pub next_domain: String,
+    pub types: Vec<RecordType>,
+}
+
+// RFC 4034 section 2
+#[derive(Debug, Clone)]
+pub struct Dnskey {
+    pub flags: u16,
+    pub protocol: u8,
+    pub algorithm: u8,
+    pub public_key: Vec<u8>,
+}
+
+// RFC 5155 section 3
+#[derive(Debug, Clone)]
+pub struct Nsec3 {
+    pub hash_algorithm: u8,
+    pub flags: u8,
+    pub iterations: u16,
+    pub salt: Vec<u8>,
+    pub next_hashed: Vec<u8>,
+    pub types: Vec<RecordType>,
+}
+
+// RFC 6698
+#[derive(Debug, Clone)]
+pub struct Tlsa {
+    pub usage: u8,
+    pub selector: u8,
+    pub matching_type: u8,
+    pub data: Vec<u8>,
+}
+
+// RFC 8659
+#[derive(Debug, Clone)]
+pub struct Caa {
+    pub flags: u8,
+    pub tag: String,
+    pub value: Vec<u8>,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum SvcbParamKey {
+    MANDATORY,
     ALPN,
     NODEFAULTALPN,
     PORT,
@@ -127,11 +338,11 @@ pub enum SvcbParamKey {
 }
 
 impl TryFrom<u16> for SvcbParamKey {
-    type Error = std::io::Error;
+    type Error = MessageError;
 
     fn try_from(value: u16) -> Result<SvcbParamKey, Self::Error> {
 	match value {
-	    0 => panic!("oops"),
+	    0 => Ok(SvcbParamKey::MANDATORY),
 	    1 => Ok(SvcbParamKey::ALPN),
 	    2 => Ok(SvcbParamKey::NODEFAULTALPN),
 	    3 => Ok(SvcbParamKey::PORT),
@@ -146,6 +357,7 @@ impl TryFrom<u16> for SvcbParamKey {
 impl From<SvcbParamKey> for u16 {
     fn from(param: SvcbParamKey) -> u16 {
 	match param {
+	    SvcbParamKey::MANDATORY => 0,
 	    SvcbParamKey::ALPN => 1,
 	    SvcbParamKey::NODEFAULTALPN => 2,
 	    SvcbParamKey::PORT => 3,
@@ -181,3 +393,362 @@ pub struct SvcbParam {
     pub value: Vec<u8>,
 }
 
+
+impl std::str::FromStr for RecordType {
+    type Err = MessageError;
+
+    // Accepts mnemonics, RFC 3597 "TYPEnnn" and plain numbers.
+    fn from_str(s: &str) -> Result<RecordType, Self::Err> {
+	let s = s.to_ascii_uppercase();
+	if let Some(rtype) = NAMED_TYPES.iter().find(|t| t.to_string() == s) {
+	    return Ok(*rtype);
+	}
+	let value = match s.as_str() {
+	    "ANY" => 255,
+	    _ => s.strip_prefix("TYPE").unwrap_or(&s).parse::<u16>()
+		.map_err(|_| MessageError::Unsupported(format!("RecordType {:?}", s)))?,
+	};
+	RecordType::try_from(value)
+    }
+}
+
+// The mnemonic, or "TYPEnnn" for the others.
+impl std::fmt::Display for RecordType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	match self {
+	    RecordType::UNKNOWN(n) => write!(f, "TYPE{}", n),
+	    _ => write!(f, "{:?}", self),
+	}
+    }
+}
+
+fn write_hex(f: &mut std::fmt::Formatter, data: &[u8]) -> std::fmt::Result {
+    for b in data {
+	write!(f, "{:02X}", b)?;
+    }
+    Ok(())
+}
+
+fn write_base64(f: &mut std::fmt::Formatter, data: &[u8]) -> std::fmt::Result {
+    use base64::Engine;
+    write!(f, "{}", base64::engine::general_purpose::STANDARD.encode(data))
+}
+
+// Base 32 with the extended hex alphabet and no padding, RFC 5155
+// section 3.3.
+fn write_base32hex(f: &mut std::fmt::Formatter, data: &[u8]) -> std::fmt::Result {
+    const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+    let mut bits = 0u32;
+    let mut nbits = 0;
+    for b in data {
+	bits = (bits << 8) | *b as u32;
+	nbits += 8;
+	while nbits >= 5 {
+	    nbits -= 5;
+	    write!(f, "{}", ALPHABET[(bits >> nbits) as usize & 0x1f] as char)?;
+	}
+    }
+    if nbits > 0 {
+	write!(f, "{}", ALPHABET[(bits << (5 - nbits)) as usize & 0x1f] as char)?;
+    }
+    Ok(())
+}
+
+// RRSIG times as YYYYMMDDHHmmSS in UTC, RFC 4034 section 3.2.
+fn write_time(f: &mut std::fmt::Formatter, time: u32) -> std::fmt::Result {
+    let secs = time % 86400;
+    // Civil date from days since the epoch, shifted so that years start
+    // in March and the leap day comes last.
+    let days = (time / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let doe = days % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    write!(f, "{:04}{:02}{:02}{:02}{:02}{:02}", year, month, day,
+	   secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn write_types(f: &mut std::fmt::Formatter, types: &[RecordType]) -> std::fmt::Result {
+    for t in types {
+	write!(f, " {}", t)?;
+    }
+    Ok(())
+}
+
+fn write_quoted(f: &mut std::fmt::Formatter, data: &[u8]) -> std::fmt::Result {
+    write!(f, "\"")?;
+    write_escaped(f, data)?;
+    write!(f, "\"")
+}
+
+// Writes data as the contents of a quoted character-string, RFC 1035
+// section 5.1.
+fn write_escaped(f: &mut std::fmt::Formatter, data: &[u8]) -> std::fmt::Result {
+    for b in data {
+	match b {
+	    b'"' | b'\\' => write!(f, "\\{}", *b as char)?,
+	    0x20..=0x7e => write!(f, "{}", *b as char)?,
+	    _ => write!(f, "\\{:03}", b)?,
+	}
+    }
+    Ok(())
+}
+
+impl std::fmt::Display for SvcbParamKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	match self {
+	    SvcbParamKey::MANDATORY => write!(f, "mandatory"),
+	    SvcbParamKey::ALPN => write!(f, "alpn"),
+	    SvcbParamKey::NODEFAULTALPN => write!(f, "no-default-alpn"),
+	    SvcbParamKey::PORT => write!(f, "port"),
+	    SvcbParamKey::IPV4HINT => write!(f, "ipv4hint"),
+	    SvcbParamKey::ECHCONFIG => write!(f, "ech"),
+	    SvcbParamKey::IPV6HINT => write!(f, "ipv6hint"),
+	    SvcbParamKey::KEY(n) => write!(f, "key{}", n),
+	}
+    }
+}
+
+// Presentation format from RFC 9460 section 2.1. Values that don't parse
+// for their key are shown in the generic quoted form.
+impl std::fmt::Display for SvcbParam {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	let v = &self.value;
+	let list: Option<Vec<String>> = match self.key {
+	    SvcbParamKey::MANDATORY if v.len().is_multiple_of(2) => Some(v.chunks(2)
+		.map(|k| SvcbParamKey::try_from(u16::from_be_bytes([k[0], k[1]])).map(|k| k.to_string()).unwrap_or_default())
+		.collect()),
+	    SvcbParamKey::ALPN => {
+		let mut ids = Vec::new();
+		let mut rest = &v[..];
+		while let Some((len, tail)) = rest.split_first() {
+		    if tail.len() < *len as usize {
+			break;
+		    }
+		    ids.push(String::from_utf8_lossy(&tail[..*len as usize]).replace(',', "\\,"));
+		    rest = &tail[*len as usize..];
+		}
+		if rest.is_empty() { Some(ids) } else { None }
+	    },
+	    SvcbParamKey::NODEFAULTALPN if v.is_empty() => return write!(f, "{}", self.key),
+	    SvcbParamKey::PORT if v.len() == 2 => Some(vec![u16::from_be_bytes([v[0], v[1]]).to_string()]),
+	    SvcbParamKey::IPV4HINT if v.len().is_multiple_of(4) => Some(v.chunks(4)
+		.map(|a| Ipv4Addr::new(a[0], a[1], a[2], a[3]).to_string())
+		.collect()),
+	    SvcbParamKey::IPV6HINT if v.len().is_multiple_of(16) => Some(v.chunks(16)
+		.map(|a| Ipv6Addr::from(<[u8; 16]>::try_from(a).unwrap()).to_string())
+		.collect()),
+	    SvcbParamKey::ECHCONFIG => {
+		use base64::Engine;
+		Some(vec![base64::engine::general_purpose::STANDARD.encode(v)])
+	    },
+	    _ => None,
+	};
+	match list {
+	    Some(list) => write!(f, "{}={}", self.key, list.join(",")),
+	    None => {
+		write!(f, "key{}=\"", u16::from(self.key))?;
+		write_escaped(f, v)?;
+		write!(f, "\"")
+	    },
+	}
+    }
+}
+
+impl ResourceData {
+    // The character-strings of a TXT record as one value, the way SPF
+    // and DKIM records longer than 255 bytes are meant to be read.
+    pub fn txt_joined(&self) -> Option<Vec<u8>> {
+	match self {
+	    ResourceData::Txt(strings) => Some(strings.concat()),
+	    _ => None,
+	}
+    }
+
+    // txt_joined as text, invalid UTF-8 replaced.
+    pub fn txt_lossy(&self) -> Option<String> {
+	self.txt_joined().map(|data| String::from_utf8_lossy(&data).into_owned())
+    }
+}
+
+// Zone file presentation format of the RDATA.
+impl std::fmt::Display for ResourceData {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	match self {
+	    ResourceData::IPv4(addr) => write!(f, "{}", addr),
+	    ResourceData::IPv6(addr) => write!(f, "{}", addr),
+	    ResourceData::Ns(name) | ResourceData::CName(name) | ResourceData::Ptr(name) => write!(f, "{}", name),
+	    ResourceData::Soa(soa) => write!(f, "{} {} {} {} {} {} {}", soa.mname, soa.rname, soa.serial,
+					     soa.refresh, soa.retry, soa.expire, soa.minimum),
+	    ResourceData::Mx(mx) => write!(f, "{} {}", mx.preference, mx.exchange),
+	    ResourceData::Names(names) => {
+		if let Some(n) = names.number {
+		    write!(f, "{} ", n)?;
+		}
+		write!(f, "{}", names.names.join(" "))
+	    },
+	    ResourceData::Txt(strings) => {
+		for (i, s) in strings.iter().enumerate() {
+		    if i > 0 {
+			write!(f, " ")?;
+		    }
+		    write_quoted(f, s)?;
+		}
+		Ok(())
+	    },
+	    ResourceData::Srv(srv) => write!(f, "{} {} {} {}", srv.priority, srv.weight, srv.port, srv.target),
+	    ResourceData::Naptr(naptr) => {
+		write!(f, "{} {} ", naptr.order, naptr.preference)?;
+		write_quoted(f, &naptr.flags)?;
+		write!(f, " ")?;
+		write_quoted(f, &naptr.services)?;
+		write!(f, " ")?;
+		write_quoted(f, &naptr.regexp)?;
+		write!(f, " {}", naptr.replacement)
+	    },
+	    ResourceData::Ds(ds) => {
+		write!(f, "{} {} {} ", ds.key_tag, ds.algorithm, ds.digest_type)?;
+		write_hex(f, &ds.digest)
+	    },
+	    ResourceData::Sshfp(sshfp) => {
+		write!(f, "{} {} ", sshfp.algorithm, sshfp.fp_type)?;
+		write_hex(f, &sshfp.fingerprint)
+	    },
+	    ResourceData::Rrsig(sig) => {
+		write!(f, "{} {} {} {} ", sig.type_covered, sig.algorithm, sig.labels, sig.original_ttl)?;
+		write_time(f, sig.expiration)?;
+		write!(f, " ")?;
+		write_time(f, sig.inception)?;
+		write!(f, " {} {} ", sig.key_tag, sig.signer_name)?;
+		write_base64(f, &sig.signature)
+	    },
+	    ResourceData::Nsec(nsec) => {
+		write!(f, "{}", nsec.next_domain)?;
+		write_types(f, &nsec.types)
+	    },
+	    ResourceData::Dnskey(key) => {
+		write!(f, "{} {} {} ", key.flags, key.protocol, key.algorithm)?;
+		write_base64(f, &key.public_key)
+	    },
+	    ResourceData::Nsec3(nsec3) => {
+		write!(f, "{} {} {} ", nsec3.hash_algorithm, nsec3.flags, nsec3.iterations)?;
+		if nsec3.salt.is_empty() {
+		    write!(f, "-")?;
+		} else {
+		    write_hex(f, &nsec3.salt)?;
+		}
+		write!(f, " ")?;
+		write_base32hex(f, &nsec3.next_hashed)?;
+		write_types(f, &nsec3.types)
+	    },
+	    ResourceData::Tlsa(tlsa) => {
+		write!(f, "{} {} {} ", tlsa.usage, tlsa.selector, tlsa.matching_type)?;
+		write_hex(f, &tlsa.data)
+	    },
+	    ResourceData::Caa(caa) => {
+		write!(f, "{} {} ", caa.flags, caa.tag)?;
+		write_quoted(f, &caa.value)
+	    },
+	    ResourceData::Svcb(svcb) | ResourceData::Https(svcb) => match &svcb.form {
+		SvcbForm::ALIASFORM => write!(f, "0 {}", svcb.domain_name),
+		SvcbForm::SERVICEFORM(form) => {
+		    write!(f, "{} {}", form.field_priority, svcb.domain_name)?;
+		    for p in &form.params {
+			write!(f, " {}", p)?;
+		    }
+		    Ok(())
+		},
+	    },
+	    // Generic form of RFC 3597 section 5.
+	    ResourceData::Unknown(data) => {
+		write!(f, "\\# {}", data.len())?;
+		if !data.is_empty() {
+		    write!(f, " ")?;
+		    write_hex(f, data)?;
+		}
+		Ok(())
+	    },
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_type_from_str() {
+	assert!(matches!("aaaa".parse::<RecordType>(), Ok(RecordType::AAAA)));
+	assert!(matches!("TYPE65".parse::<RecordType>(), Ok(RecordType::HTTPS)));
+	assert!(matches!("srv".parse::<RecordType>(), Ok(RecordType::SRV)));
+	assert!(matches!("TYPE14".parse::<RecordType>(), Ok(RecordType::MINFO)));
+	assert!(matches!("99".parse::<RecordType>(), Ok(RecordType::UNKNOWN(99))));
+	assert_eq!(RecordType::UNKNOWN(99).to_string(), "TYPE99");
+	assert_eq!(RecordType::NSEC3.to_string(), "NSEC3");
+	assert!("BOGUS".parse::<RecordType>().is_err());
+    }
+
+    #[test]
+    fn test_txt_joined() {
+	let value = "v=DKIM1; k=rsa; p=".to_owned() + &"A".repeat(300);
+	let txt = ResourceData::Txt(value.as_bytes().chunks(255).map(|c| c.to_vec()).collect());
+	assert_eq!(txt.txt_joined().unwrap(), value.as_bytes());
+	assert_eq!(txt.txt_lossy().unwrap(), value);
+	let txt = ResourceData::Txt(vec![b"caf\xc3".to_vec(), b"\xa9 \xff".to_vec()]);
+	assert_eq!(txt.txt_lossy().unwrap(), "caf\u{e9} \u{fffd}");
+	assert!(ResourceData::IPv4(Ipv4Addr::LOCALHOST).txt_joined().is_none());
+    }
+
+    #[test]
+    fn test_display() {
+	let txt = ResourceData::Txt(vec![b"say \"hi\"\n".to_vec(), b"\xff".to_vec()]);
+	assert_eq!(txt.to_string(), "\"say \\\"hi\\\"\\010\" \"\\255\"");
+	let svcb = ResourceData::Https(Svcb{
+	    domain_name: ".".to_owned(),
+	    form: SvcbForm::SERVICEFORM(SvcbServiceForm{
+		field_priority: 1,
+		params: vec![
+		    SvcbParam{key: SvcbParamKey::MANDATORY, value: vec![0, 1]},
+		    SvcbParam{key: SvcbParamKey::ALPN, value: b"\x02h2\x02h3".to_vec()},
+		    SvcbParam{key: SvcbParamKey::NODEFAULTALPN, value: Vec::new()},
+		    SvcbParam{key: SvcbParamKey::PORT, value: vec![1, 187]},
+		    SvcbParam{key: SvcbParamKey::IPV4HINT, value: vec![1, 2, 3, 4, 5, 6, 7, 8]},
+		    SvcbParam{key: SvcbParamKey::IPV6HINT, value: Ipv6Addr::LOCALHOST.octets().to_vec()},
+		    SvcbParam{key: SvcbParamKey::KEY(9), value: b"a\"b".to_vec()},
+		    SvcbParam{key: SvcbParamKey::PORT, value: vec![1]},
+		],
+	    }),
+	});
+	assert_eq!(svcb.to_string(), "1 . mandatory=alpn alpn=h2,h3 no-default-alpn port=443 \
+				      ipv4hint=1.2.3.4,5.6.7.8 ipv6hint=::1 key9=\"a\\\"b\" key3=\"\\001\"");
+	let rrsig = ResourceData::Rrsig(Rrsig{
+	    type_covered: RecordType::A,
+	    algorithm: 13,
+	    labels: 2,
+	    original_ttl: 300,
+	    expiration: 1709251199,
+	    inception: 951782400,
+	    key_tag: 12345,
+	    signer_name: "example.com.".to_owned(),
+	    signature: vec![1, 2, 3],
+	});
+	assert_eq!(rrsig.to_string(), "A 13 2 300 20240229235959 20000229000000 12345 example.com. AQID");
+	let nsec3 = ResourceData::Nsec3(Nsec3{
+	    hash_algorithm: 1,
+	    flags: 0,
+	    iterations: 0,
+	    salt: Vec::new(),
+	    next_hashed: b"foobar".to_vec(),
+	    types: vec![RecordType::A, RecordType::RRSIG, RecordType::UNKNOWN(1234)],
+	});
+	assert_eq!(nsec3.to_string(), "1 0 0 - CPNMUOJ1E8 A RRSIG TYPE1234");
+	assert_eq!(ResourceData::Unknown(vec![0xa, 0, 0, 1]).to_string(), "\\# 4 0A000001");
+	assert_eq!(ResourceData::Unknown(Vec::new()).to_string(), "\\# 0");
+	let caa = ResourceData::Caa(Caa{flags: 0, tag: "issue".to_owned(), value: b"ca.example".to_vec()});
+	assert_eq!(caa.to_string(), "0 issue \"ca.example\"");
+    }
+}
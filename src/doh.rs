@@ -0,0 +1,210 @@
+use hyper::body::HttpBody;
+use hyper::header::{ACCEPT, CACHE_CONTROL, CONTENT_TYPE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, HeaderMap, Method, Request, Response};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls;
+
+use crate::json;
+use crate::tls;
+use crate::message::Message;
+use crate::{encode_response, parse_query, Resolver};
+
+// DNS over HTTPS, RFC 8484.
+
+const DNS_MESSAGE: &str = "application/dns-message";
+// A DNS message can't be longer than this, RFC 8484 section 6.
+const MAX_MESSAGE_SIZE: usize = 65535;
+
+pub fn error(status: u16, reason: &'static str) -> Response<Body> {
+    debug!("DoH: {}", reason);
+    Response::builder().status(status).body(Body::from(reason)).unwrap()
+}
+
+// Strips parameters from a media type, "text/html; charset=utf-8" ->
+// "text/html".
+fn media_type(value: &str) -> String {
+    value.split(';').next().unwrap_or("").trim().to_ascii_lowercase()
+}
+
+// True unless an Accept header is present and excludes DNS messages.
+fn accepts_dns_message(headers: &HeaderMap) -> bool {
+    let mut present = false;
+    for value in headers.get_all(ACCEPT) {
+	present = true;
+	let value = match value.to_str() {
+	    Ok(value) => value,
+	    Err(_) => continue,
+	};
+	if value.split(',').map(media_type)
+	    .any(|t| t == DNS_MESSAGE || t == "application/*" || t == "*/*") {
+	    return true;
+	}
+    }
+    !present
+}
+
+fn has_dns_message_body(headers: &HeaderMap) -> bool {
+    headers.get(CONTENT_TYPE)
+	.and_then(|v| v.to_str().ok())
+	.is_some_and(|v| media_type(v) == DNS_MESSAGE)
+}
+
+// The freshness lifetime of a response is the lowest TTL in it, RFC 8484
+// section 5.1. Negative answers carry their SOA with the negative TTL
+// already applied.
+pub fn max_age(answer: &Message) -> u32 {
+    answer.answers.iter()
+	.chain(answer.nameservers.iter())
+	.chain(answer.additional.iter())
+	.map(|r| r.ttl)
+	.min()
+	.unwrap_or(0)
+}
+
+async fn read_body(mut body: Body) -> Result<Vec<u8>, Response<Body>> {
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+	let chunk = chunk.map_err(|_| error(400, "Failed to read request body"))?;
+	if data.len() + chunk.len() > MAX_MESSAGE_SIZE {
+	    return Err(error(413, "DNS message too large"));
+	}
+	data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+// Extracts the DNS message from a GET dns= parameter or a POST body.
+async fn read_query(req: Request<Body>) -> Result<Vec<u8>, Response<Body>> {
+    match *req.method() {
+	Method::GET => {
+	    let params: HashMap<String, String> = req.uri().query().map(|v| {
+		url::form_urlencoded::parse(v.as_bytes()).into_owned().collect()
+	    }).unwrap_or_default();
+	    let dns = params.get("dns").ok_or_else(|| error(400, "Missing dns parameter"))?;
+	    base64_url::decode(dns).map_err(|_| error(400, "Invalid base64 in dns parameter"))
+	},
+	Method::POST => {
+	    if !has_dns_message_body(req.headers()) {
+		return Err(error(415, "Content-Type must be application/dns-message"));
+	    }
+	    read_body(req.into_body()).await
+	},
+	_ => Err(error(405, "Method not allowed")),
+    }
+}
+
+fn dns_response(answer: &mut Message) -> Response<Body> {
+    let data = match encode_response(answer) {
+	Some(data) => data,
+	None => return error(500, "Failed to encode response"),
+    };
+    Response::builder()
+	.header(CONTENT_TYPE, DNS_MESSAGE)
+	.header(CACHE_CONTROL, format!("max-age={}", max_age(answer)))
+	.body(Body::from(data))
+	.unwrap()
+}
+
+async fn handle(req: Request<Body>, resolver: Arc<Resolver>) -> Result<Response<Body>, Infallible> {
+    if req.uri().path() == "/resolve" {
+	return Ok(json::handle(req, resolver).await);
+    }
+    if !accepts_dns_message(req.headers()) {
+	return Ok(error(406, "Only application/dns-message is supported"));
+    }
+    let payload = match read_query(req).await {
+	Ok(payload) => payload,
+	Err(rsp) => return Ok(rsp),
+    };
+    let message = match parse_query(&payload) {
+	Ok(message) => message,
+	Err(Some(mut answer)) => return Ok(dns_response(&mut answer)),
+	Err(None) => return Ok(error(400, "Malformed DNS message")),
+    };
+    debug!("DoH Question: {:?}", message);
+    let mut answer = resolver.resolve(&message).await;
+    debug!("DoH Answer: {:?}", answer);
+    Ok(dns_response(&mut answer))
+}
+
+// ALPN protocols offered with TLS, RFC 8484 section 5.2 recommends HTTP/2.
+pub const ALPN: [&[u8]; 2] = [b"h2", b"http/1.1"];
+
+// Serves HTTP/1.1 and, for clients that start with the HTTP/2 preface,
+// HTTP/2 on one connection.
+async fn serve<S>(stream: S, h2: bool, resolver: Arc<Resolver>) -> Result<(), hyper::Error>
+where S: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    let service = service_fn(move |req| {
+	handle(req, resolver.clone())
+    });
+    Http::new().http2_only(h2).serve_connection(stream, service).await
+}
+
+async fn handle_conn(stream: tokio::net::TcpStream, tls: Option<tokio_rustls::TlsAcceptor>,
+		     resolver: Arc<Resolver>) -> Result<(), std::io::Error> {
+    let acceptor = match tls {
+	Some(acceptor) => acceptor,
+	None => return serve(stream, false, resolver).await.map_err(std::io::Error::other),
+    };
+    let stream = tls::accept(&acceptor, stream).await?;
+    let h2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+    serve(stream, h2, resolver).await.map_err(std::io::Error::other)
+}
+
+// Listens for DoH over plain HTTP, or HTTPS when a TLS configuration is
+// given.
+pub async fn run(addr: SocketAddr, tls: Option<Arc<rustls::ServerConfig>>,
+		 resolver: Arc<Resolver>) -> Result<(), std::io::Error> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("DoH listening on {}{}", addr, if tls.is_some() { " (TLS)" } else { "" });
+    let tls = tls.map(tokio_rustls::TlsAcceptor::from);
+    tokio::spawn(async move {
+	loop {
+	    let (stream, src) = match listener.accept().await {
+		Ok(conn) => conn,
+		Err(e) => {
+		    debug!("DoH accept failed: {}", e);
+		    continue;
+		},
+	    };
+	    let tls = tls.clone();
+	    let resolver = resolver.clone();
+	    tokio::spawn(async move {
+		if let Err(e) = handle_conn(stream, tls, resolver).await {
+		    debug!("DoH connection from {} closed: {}", src, e);
+		}
+	    });
+	}
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn test_content_types() {
+	let mut headers = HeaderMap::new();
+	assert!(accepts_dns_message(&headers));
+	assert!(!has_dns_message_body(&headers));
+	headers.insert(ACCEPT, HeaderValue::from_static("text/html"));
+	assert!(!accepts_dns_message(&headers));
+	headers.insert(ACCEPT, HeaderValue::from_static("text/html, Application/DNS-Message;q=0.9"));
+	assert!(accepts_dns_message(&headers));
+	headers.insert(ACCEPT, HeaderValue::from_static("*/*"));
+	assert!(accepts_dns_message(&headers));
+	headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/dns-message"));
+	assert!(has_dns_message_body(&headers));
+	headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/dns-json"));
+	assert!(!has_dns_message_body(&headers));
+    }
+}
@@ -0,0 +1,42 @@
+use log::{debug, info};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio_rustls::rustls;
+
+use crate::tls;
+use crate::{handle_tcp_conn, Resolver};
+
+// DNS over TLS, RFC 7858. The same framing as plain TCP inside TLS.
+
+// ALPN protocol id from RFC 7858 section 3.2.
+pub const ALPN: [&[u8]; 1] = [b"dot"];
+
+pub async fn run(addr: SocketAddr, config: Arc<rustls::ServerConfig>,
+		 resolver: Arc<Resolver>) -> Result<(), std::io::Error> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("DoT listening on {}", addr);
+    let acceptor = tokio_rustls::TlsAcceptor::from(config);
+    tokio::spawn(async move {
+	loop {
+	    let (stream, src) = match listener.accept().await {
+		Ok(conn) => conn,
+		Err(e) => {
+		    debug!("DoT accept failed: {}", e);
+		    continue;
+		},
+	    };
+	    let acceptor = acceptor.clone();
+	    let resolver = resolver.clone();
+	    tokio::spawn(async move {
+		let result = match tls::accept(&acceptor, stream).await {
+		    Ok(stream) => handle_tcp_conn(stream, resolver).await,
+		    Err(e) => Err(e),
+		};
+		if let Err(e) = result {
+		    debug!("DoT connection from {} closed: {}", src, e);
+		}
+	    });
+	}
+    });
+    Ok(())
+}
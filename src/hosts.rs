@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::dns;
+
+// Hosts entries change whenever the file is edited, keep clients from
+// holding on to them for long.
+const TTL: u32 = 60;
+
+// Local zone built from a hosts(5) file. Names listed there are answered
+// authoritatively for A and AAAA, and their addresses for PTR.
+#[derive(Debug, Default)]
+pub struct Hosts {
+    v4: HashMap<String, Vec<Ipv4Addr>>,
+    v6: HashMap<String, Vec<Ipv6Addr>>,
+    ptr: HashMap<String, Vec<String>>,
+}
+
+fn normalize(name: &str) -> String {
+    let name = name.to_lowercase();
+    if name.ends_with('.') {
+	name
+    } else {
+	name + "."
+    }
+}
+
+pub fn reverse_name(addr: &IpAddr) -> String {
+    match addr {
+	IpAddr::V4(a) => {
+	    let o = a.octets();
+	    format!("{}.{}.{}.{}.in-addr.arpa.", o[3], o[2], o[1], o[0])
+	},
+	IpAddr::V6(a) => {
+	    let mut name = String::new();
+	    for b in a.octets().iter().rev() {
+		name += &format!("{:x}.{:x}.", b & 0xf, b >> 4);
+	    }
+	    name + "ip6.arpa."
+	},
+    }
+}
+
+fn record(name: &str, rtype: dns::RecordType, data: dns::ResourceData) -> dns::ResourceRecord {
+    dns::ResourceRecord{
+	name: name.to_owned(),
+	rtype,
+	class: dns::RecordClass::IN,
+	ttl: TTL,
+	data,
+    }
+}
+
+impl Hosts {
+    pub fn new() -> Hosts {
+	Hosts::default()
+    }
+
+    // Reads the hosts file, returning the entries that parsed along with
+    // an error message for every line that did not.
+    pub fn load(path: &std::path::Path) -> Result<(Hosts, Vec<String>), std::io::Error> {
+	let file = std::fs::File::open(path)?;
+	let mut hosts = Hosts::new();
+	let mut errors = Vec::new();
+	for (i, l) in std::io::BufReader::new(file).lines().enumerate() {
+	    if let Err(e) = hosts.add_line(&l?) {
+		errors.push(format!("{}:{}: {}", path.display(), i + 1, e));
+	    }
+	}
+	Ok((hosts, errors))
+    }
+
+    // Parses "<address> <name> [<name>...]", '#' starts a comment.
+    pub fn add_line(&mut self, l: &str) -> Result<(), String> {
+	let line = l.split('#').next().unwrap_or("");
+	let mut parts = line.split_whitespace();
+	let addr = match parts.next() {
+	    Some(addr) => addr,
+	    None => return Ok(()),
+	};
+	let addr = addr.parse::<IpAddr>().map_err(|e| format!("{:?}: {}", addr, e))?;
+	let names: Vec<String> = parts.map(normalize).collect();
+	if names.is_empty() {
+	    return Err(format!("No names for {}", addr));
+	}
+	for name in &names {
+	    match addr {
+		IpAddr::V4(a) => self.v4.entry(name.clone()).or_default().push(a),
+		IpAddr::V6(a) => self.v6.entry(name.clone()).or_default().push(a),
+	    }
+	}
+	self.ptr.entry(reverse_name(&addr)).or_default().extend(names);
+	Ok(())
+    }
+
+    // Returns the answers for the question if the name is ours. A name
+    // with only IPv4 addresses gets an empty (NODATA) AAAA answer and
+    // vice versa.
+    pub fn lookup(&self, name: &str, qtype: dns::RecordType) -> Option<Vec<dns::ResourceRecord>> {
+	let key = normalize(name);
+	match qtype {
+	    dns::RecordType::A | dns::RecordType::AAAA => {
+		if !self.v4.contains_key(&key) && !self.v6.contains_key(&key) {
+		    return None;
+		}
+		let mut answers = Vec::new();
+		if let dns::RecordType::A = qtype {
+		    for a in self.v4.get(&key).into_iter().flatten() {
+			answers.push(record(name, qtype, dns::ResourceData::IPv4(*a)));
+		    }
+		} else {
+		    for a in self.v6.get(&key).into_iter().flatten() {
+			answers.push(record(name, qtype, dns::ResourceData::IPv6(*a)));
+		    }
+		}
+		Some(answers)
+	    },
+	    dns::RecordType::PTR => {
+		let names = self.ptr.get(&key)?;
+		Some(names.iter()
+		     .map(|n| record(name, qtype, dns::ResourceData::Ptr(n.clone())))
+		     .collect())
+	    },
+	    _ => None,
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts() -> Hosts {
+	let mut h = Hosts::new();
+	for l in ["# comment", "", "10.0.0.1 router router.lan # trailing",
+		  "10.0.0.2\tnas", "10.0.0.3 nas", "fd00::1 nas"] {
+	    h.add_line(l).unwrap();
+	}
+	h
+    }
+
+    #[test]
+    fn test_forward() {
+	let h = hosts();
+	let a = h.lookup("Router.lan.", dns::RecordType::A).unwrap();
+	assert_eq!(a.len(), 1);
+	assert_eq!(a[0].name, "Router.lan.");
+	assert!(matches!(a[0].data, dns::ResourceData::IPv4(ip) if ip == Ipv4Addr::new(10, 0, 0, 1)));
+	assert_eq!(h.lookup("nas", dns::RecordType::A).unwrap().len(), 2);
+	assert_eq!(h.lookup("nas.", dns::RecordType::AAAA).unwrap().len(), 1);
+	assert!(h.lookup("router.", dns::RecordType::AAAA).unwrap().is_empty());
+	assert!(h.lookup("router.", dns::RecordType::MX).is_none());
+	assert!(h.lookup("example.com.", dns::RecordType::A).is_none());
+    }
+
+    #[test]
+    fn test_reverse() {
+	let h = hosts();
+	let ptr = h.lookup("1.0.0.10.in-addr.arpa.", dns::RecordType::PTR).unwrap();
+	assert_eq!(ptr.len(), 2);
+	assert!(matches!(&ptr[0].data, dns::ResourceData::Ptr(n) if n == "router."));
+	assert!(matches!(&ptr[1].data, dns::ResourceData::Ptr(n) if n == "router.lan."));
+	let name = reverse_name(&"fd00::1".parse().unwrap());
+	assert_eq!(name, "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.d.f.ip6.arpa.");
+	assert_eq!(h.lookup(&name, dns::RecordType::PTR).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_invalid() {
+	let mut h = Hosts::new();
+	assert!(h.add_line("10.0.0.300 bad").is_err());
+	assert!(h.add_line("10.0.0.1").is_err());
+	assert!(h.v4.is_empty() && h.v6.is_empty());
+    }
+}
@@ -0,0 +1,203 @@
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response};
+use log::debug;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::dns;
+use crate::doh;
+use crate::message::{self, Message};
+use crate::{Resolver, EDNS_PAYLOAD_SIZE};
+
+// JSON API in the application/dns-json format of Google Public DNS and
+// Cloudflare, GET /resolve?name=example.com&type=AAAA.
+
+const DNS_JSON: &str = "application/dns-json";
+
+#[derive(Serialize)]
+struct JsonQuestion {
+    name: String,
+    #[serde(rename = "type")]
+    qtype: u16,
+}
+
+#[derive(Serialize)]
+struct JsonRecord {
+    name: String,
+    #[serde(rename = "type")]
+    rtype: u16,
+    #[serde(rename = "TTL")]
+    ttl: u32,
+    data: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct JsonMessage {
+    status: u32,
+    #[serde(rename = "TC")]
+    tc: bool,
+    #[serde(rename = "RD")]
+    rd: bool,
+    #[serde(rename = "RA")]
+    ra: bool,
+    #[serde(rename = "AD")]
+    ad: bool,
+    #[serde(rename = "CD")]
+    cd: bool,
+    question: Vec<JsonQuestion>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    answer: Vec<JsonRecord>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    authority: Vec<JsonRecord>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    additional: Vec<JsonRecord>,
+}
+
+// TXT data is given as the joined text rather than quoted strings.
+fn records(rrs: &[dns::ResourceRecord]) -> Vec<JsonRecord> {
+    rrs.iter().map(|rr| JsonRecord{
+	name: rr.name.clone(),
+	rtype: rr.rtype.into(),
+	ttl: rr.ttl,
+	data: rr.data.txt_lossy().unwrap_or_else(|| rr.data.to_string()),
+    }).collect()
+}
+
+impl From<&Message> for JsonMessage {
+    fn from(m: &Message) -> JsonMessage {
+	JsonMessage{
+	    status: m.full_rcode(),
+	    tc: m.tc != 0,
+	    rd: m.rd != 0,
+	    ra: m.ra != 0,
+	    ad: m.ad != 0,
+	    cd: m.cd != 0,
+	    question: m.questions.iter().map(|q| JsonQuestion{
+		name: q.name.clone(),
+		qtype: q.qtype.into(),
+	    }).collect(),
+	    answer: records(&m.answers),
+	    authority: records(&m.nameservers),
+	    additional: records(&m.additional),
+	}
+    }
+}
+
+// Checks the label and name lengths of RFC 1035 section 2.3.4 and returns
+// the name in absolute form.
+fn parse_name(name: &str) -> Option<String> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() {
+	return Some(".".to_owned());
+    }
+    if name.len() > 253 || name.split('.').any(|l| l.is_empty() || l.len() > 63) {
+	return None;
+    }
+    Some(format!("{}.", name))
+}
+
+fn parse_flag(value: Option<&String>) -> Result<bool, &'static str> {
+    match value.map(|v| v.to_ascii_lowercase()).as_deref() {
+	None | Some("") | Some("0") | Some("false") => Ok(false),
+	Some("1") | Some("true") => Ok(true),
+	_ => Err("Invalid flag parameter"),
+    }
+}
+
+fn parse_params(req: &Request<Body>) -> Result<Message, &'static str> {
+    let params: HashMap<String, String> = req.uri().query().map(|v| {
+	url::form_urlencoded::parse(v.as_bytes()).into_owned().collect()
+    }).unwrap_or_default();
+    let name = params.get("name").ok_or("Missing name parameter")?;
+    let name = parse_name(name).ok_or("Invalid name parameter")?;
+    let qtype = match params.get("type") {
+	Some(t) => t.parse().map_err(|_| "Invalid type parameter")?,
+	None => dns::RecordType::A,
+    };
+    let mut query = Message::new();
+    query.rd = 1;
+    query.cd = parse_flag(params.get("cd"))? as u8;
+    if parse_flag(params.get("do"))? {
+	let mut edns = message::Edns::new(EDNS_PAYLOAD_SIZE);
+	edns.dnssec_ok = true;
+	query.edns = Some(edns);
+    }
+    query.questions.push(message::Question{
+	name,
+	qtype,
+	class: dns::RecordClass::IN,
+    });
+    Ok(query)
+}
+
+pub async fn handle(req: Request<Body>, resolver: Arc<Resolver>) -> Response<Body> {
+    if req.method() != Method::GET {
+	return doh::error(405, "Method not allowed");
+    }
+    let query = match parse_params(&req) {
+	Ok(query) => query,
+	Err(reason) => return doh::error(400, reason),
+    };
+    debug!("JSON Question: {:?}", query);
+    let answer = resolver.resolve(&query).await;
+    debug!("JSON Answer: {:?}", answer);
+    let body = match serde_json::to_string(&JsonMessage::from(&answer)) {
+	Ok(body) => body,
+	Err(_) => return doh::error(500, "Failed to encode response"),
+    };
+    Response::builder()
+	.header(CONTENT_TYPE, DNS_JSON)
+	.header(CACHE_CONTROL, format!("max-age={}", doh::max_age(&answer)))
+	.body(Body::from(body))
+	.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_name() {
+	assert_eq!(parse_name("example.com").as_deref(), Some("example.com."));
+	assert_eq!(parse_name("example.com.").as_deref(), Some("example.com."));
+	assert_eq!(parse_name(".").as_deref(), Some("."));
+	assert_eq!(parse_name("a..b"), None);
+	assert_eq!(parse_name(&"a".repeat(64)), None);
+	assert_eq!(parse_name(&["a"; 128].join(".")), None);
+    }
+
+    #[test]
+    fn test_render() {
+	let mut m = Message::new();
+	m.qr = 1;
+	m.rd = 1;
+	m.ra = 1;
+	m.questions.push(message::Question{
+	    name: "example.com.".to_owned(),
+	    qtype: dns::RecordType::MX,
+	    class: dns::RecordClass::IN,
+	});
+	m.answers.push(dns::ResourceRecord{
+	    name: "example.com.".to_owned(),
+	    rtype: dns::RecordType::MX,
+	    class: dns::RecordClass::IN,
+	    ttl: 300,
+	    data: dns::ResourceData::Mx(dns::Mx{preference: 10, exchange: "mail.example.com.".to_owned()}),
+	});
+	let json = serde_json::to_string(&JsonMessage::from(&m)).unwrap();
+	assert_eq!(json, "{\"Status\":0,\"TC\":false,\"RD\":true,\"RA\":true,\"AD\":false,\"CD\":false,\
+			  \"Question\":[{\"name\":\"example.com.\",\"type\":15}],\
+			  \"Answer\":[{\"name\":\"example.com.\",\"type\":15,\"TTL\":300,\
+			  \"data\":\"10 mail.example.com.\"}]}");
+	let txt = dns::ResourceRecord{
+	    name: "example.com.".to_owned(),
+	    rtype: dns::RecordType::TXT,
+	    class: dns::RecordClass::IN,
+	    ttl: 300,
+	    data: dns::ResourceData::Txt(vec![b"v=spf1 ".to_vec(), b"-all".to_vec()]),
+	};
+	assert_eq!(records(&[txt])[0].data, "v=spf1 -all");
+    }
+}
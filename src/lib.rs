@@ -0,0 +1,7 @@
+// The DNS message types and wire format, shared by the proxy and the
+// benchmarks.
+
+pub mod dns;
+pub mod message;
+mod nametree;
+pub mod view;
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// Bounds the number of questions being resolved at once, overall and per
+// client address, so a single busy client cannot take every slot.
+#[derive(Debug)]
+pub struct Limiter {
+    total: Arc<Semaphore>,
+    per_client: usize,
+    clients: Mutex<HashMap<IpAddr, usize>>,
+}
+
+// Held while a question is in flight, releases both limits when dropped.
+#[derive(Debug)]
+pub struct Permit {
+    limiter: Arc<Limiter>,
+    client: IpAddr,
+    _total: OwnedSemaphorePermit,
+}
+
+impl Limiter {
+    pub fn new(total: usize, per_client: usize) -> Arc<Limiter> {
+	Arc::new(Limiter{
+	    total: Arc::new(Semaphore::new(total)),
+	    per_client,
+	    clients: Mutex::new(HashMap::new()),
+	})
+    }
+
+    // Returns None right away if the client already has per_client
+    // questions in flight, otherwise waits for a free slot.
+    pub async fn acquire(self: &Arc<Self>, client: IpAddr) -> Option<Permit> {
+	{
+	    let mut clients = self.clients.lock().unwrap();
+	    let count = clients.entry(client).or_insert(0);
+	    if *count >= self.per_client {
+		return None;
+	    }
+	    *count += 1;
+	}
+	let mut guard = Release{limiter: self, client: Some(client)};
+	let total = self.total.clone().acquire_owned().await.ok()?;
+	guard.client = None;
+	Some(Permit{limiter: self.clone(), client, _total: total})
+    }
+
+    fn release(&self, client: IpAddr) {
+	let mut clients = self.clients.lock().unwrap();
+	if let Some(count) = clients.get_mut(&client) {
+	    *count -= 1;
+	    if *count == 0 {
+		clients.remove(&client);
+	    }
+	}
+    }
+}
+
+// Gives the client slot back if acquire is cancelled while waiting.
+struct Release<'a> {
+    limiter: &'a Limiter,
+    client: Option<IpAddr>,
+}
+
+impl Drop for Release<'_> {
+    fn drop(&mut self) {
+	if let Some(client) = self.client {
+	    self.limiter.release(client);
+	}
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+	self.limiter.release(self.client);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_limits() {
+	let limiter = Limiter::new(3, 2);
+	let a: IpAddr = "10.0.0.1".parse().unwrap();
+	let b: IpAddr = "10.0.0.2".parse().unwrap();
+	let a1 = limiter.acquire(a).await.unwrap();
+	let _a2 = limiter.acquire(a).await.unwrap();
+	assert!(limiter.acquire(a).await.is_none());
+	let _b1 = limiter.acquire(b).await.unwrap();
+	// Every slot is taken, b has to wait.
+	let wait = tokio::time::timeout(Duration::from_millis(10), limiter.acquire(b)).await;
+	assert!(wait.is_err());
+	assert_eq!(limiter.clients.lock().unwrap()[&b], 1);
+	drop(a1);
+	assert!(limiter.acquire(b).await.is_some());
+	assert!(limiter.acquire(a).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_release() {
+	let limiter = Limiter::new(1, 1);
+	let a: IpAddr = "::1".parse().unwrap();
+	drop(limiter.acquire(a).await.unwrap());
+	assert!(limiter.clients.lock().unwrap().is_empty());
+	assert!(limiter.acquire(a).await.is_some());
+    }
+}
@@ -1,18 +1,45 @@
-use hyper::server::conn::AddrStream;
-use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server, Method};
 use std::collections::HashMap;
-use std::io::{Error, ErrorKind, BufRead};
-use std::{convert::Infallible, net::SocketAddr};
-use tokio::time::Duration;
+use std::collections::hash_map::Entry;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::Poll;
+use clap::Parser;
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
 use tokio::time::timeout;
 
-mod dns;
-mod message;
-mod nametree;
+mod cache;
+mod chaos;
+mod config;
+mod doh;
+mod dot;
+mod hosts;
+mod json;
+mod limiter;
+mod reload;
+mod socketpool;
+mod tcp;
+mod tls;
+mod tokengen;
+mod transport;
+mod upstream;
 
+use dnsproxy::{dns, message, view};
 use message::Message;
 use dns::ResourceRecord;
+use upstream::UpstreamPool;
+
+// Largest response we send over UDP without EDNS, RFC 1035 section 4.2.1.
+const UDP_PAYLOAD_SIZE: usize = 512;
+// UDP payload size we advertise with EDNS, both to clients and upstreams.
+// 1232 avoids IP fragmentation on all common paths (DNS flag day 2020).
+const EDNS_PAYLOAD_SIZE: u16 = 1232;
+// Most queries a TCP or DoT client may have outstanding on one connection.
+const MAX_PIPELINED: usize = 32;
 
 fn genid() -> u16 {
     let mut buf = [0u8; 16];
@@ -20,8 +47,8 @@ fn genid() -> u16 {
     return ((buf[0] as u16) << 8) | (buf[1] as u16);
 }
 
-fn create_response(q: &Message, rcode: u32, ans: &Vec<ResourceRecord>,
-		   ns: &Vec<ResourceRecord>, ads: &Vec<ResourceRecord>) -> Message {
+fn create_response(q: &Message, rcode: u32, ans: &[ResourceRecord],
+		   ns: &[ResourceRecord], ads: &[ResourceRecord]) -> Message {
     let mut r = Message::new();
     r.id = q.id;
     r.qr = 1;
@@ -33,30 +60,78 @@ fn create_response(q: &Message, rcode: u32, ans: &Vec<ResourceRecord>,
     r.ad = 0;
     r.cd = 0;
     r.rcode = rcode;
+    if let Some(edns) = &q.edns {
+	let mut e = message::Edns::new(EDNS_PAYLOAD_SIZE);
+	e.dnssec_ok = edns.dnssec_ok;
+	r.edns = Some(e);
+    } else if rcode > 0xf {
+	// Extended rcodes can't be expressed without EDNS.
+	r.rcode = 2;
+    }
     assert!(q.questions.len() == 1);
     r.questions.push(q.questions[0].clone());
-    for a in ans {
-	match a.rtype {
-	    dns::RecordType::UNKNOWN(_) => (),
-	    _ =>  r.answers.push(a.clone()),
-	}
+    r.answers.extend_from_slice(ans);
+    r.nameservers.extend_from_slice(ns);
+    r.additional.extend_from_slice(ads);
+    r
+}
+
+// Response carrying only the query's header and question, for queries
+// that are answered with an error rcode.
+fn error_response(q: &Message, rcode: u32) -> Message {
+    let mut r = Message::new();
+    r.id = q.id;
+    r.qr = 1;
+    r.opcode = q.opcode;
+    r.rd = q.rd;
+    r.ra = 1;
+    r.rcode = rcode;
+    r.questions = q.questions.clone();
+    if q.edns.is_some() {
+	r.edns = Some(message::Edns::new(EDNS_PAYLOAD_SIZE));
+    } else if rcode > 0xf {
+	r.rcode = 2;
     }
-    for n in ns {
-	match n.rtype {
-	    dns::RecordType::UNKNOWN(_) => (),
-	    _ =>  r.nameservers.push(n.clone()),
-	}
+    r
+}
+
+// Parses a query from a client. On failure Err holds the response to send
+// back, FORMERR or NOTIMP, or None if the packet should be dropped.
+fn parse_query(data: &[u8]) -> Result<Message, Option<Box<Message>>> {
+    // Without a header there is nobody to answer.
+    let view = view::MessageView::new(data).map_err(|_| None)?;
+    let header = view.header();
+    if header.qr == 1 {
+	return Err(None);
     }
-    for a in ads {
-	match a.rtype {
-	    dns::RecordType::UNKNOWN(_) => (),
-	    _ =>  r.additional.push(a.clone()),
-	}
+    if header.opcode != 0 {
+	return Err(Some(Box::new(error_response(&header, 4)))); // NOTIMP
     }
-    r
+    if view.question_count() != 1 {
+	return Err(Some(Box::new(error_response(&header, 1)))); // FORMERR
+    }
+    Message::from(data).map_err(|e| {
+	debug!("Bad query: {}", e);
+	let rcode = match e {
+	    message::MessageError::Unsupported(_) => 4, // NOTIMP
+	    _ => 1, // FORMERR
+	};
+	Some(Box::new(error_response(&header, rcode)))
+    })
 }
 
-#[derive(Debug)]
+// Serializes a response, falling back to SERVFAIL if it can't be encoded.
+fn encode_response(r: &mut Message) -> Option<Vec<u8>> {
+    match r.into_bytes() {
+	Ok(data) => Some(data),
+	Err(e) => {
+	    error!("Failed to encode response: {}", e);
+	    error_response(r, 2).into_bytes().ok()
+	},
+    }
+}
+
+#[derive(Debug, Clone)]
 struct FwdrAnswer {
     rcode: u32,
     answers: Vec<ResourceRecord>,
@@ -68,89 +143,365 @@ struct FwdrAnswer {
 struct Question {
     name: String,
     rtype: dns::RecordType,
+    class: dns::RecordClass,
+    dnssec_ok: bool,
     rsp_to: tokio::sync::mpsc::Sender<FwdrAnswer>,
 }
 
-async fn upstream_query_a(socket: &mut tokio::net::UdpSocket, name: &str, qtype: dns::RecordType) -> Result<(), std::io::Error> {
+// Questions with the same key get the same upstream answer.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct InflightKey {
+    name: String,
+    rtype: u16,
+    class: u16,
+    dnssec_ok: bool,
+}
+
+impl InflightKey {
+    fn new(q: &Question) -> InflightKey {
+	InflightKey{
+	    name: q.name.to_ascii_lowercase(),
+	    rtype: q.rtype.into(),
+	    class: q.class.into(),
+	    dnssec_ok: q.dnssec_ok,
+	}
+    }
+}
+
+// Flips the case of letters in name at random, DNS 0x20
+// (draft-vixie-dnsext-dns0x20). A spoofed answer then also has to guess
+// the case of every letter.
+fn randomize_case(name: &str) -> String {
+    let mut bits = [0u8; 32];
+    getrandom::getrandom(&mut bits).expect("oops");
+    name.chars().enumerate().map(|(i, c)| {
+	if c.is_ascii_alphabetic() && bits[i / 8 % bits.len()] >> (i % 8) & 1 == 1 {
+	    (c as u8 ^ 0x20) as char
+	} else {
+	    c
+	}
+    }).collect()
+}
+
+// Gives the records owned by the randomized name the name as asked, so
+// the odd case doesn't reach clients or the cache.
+fn restore_case(msg: &mut Message, sent: &str, name: &str) {
+    if sent == name {
+	return;
+    }
+    let records = msg.answers.iter_mut()
+	.chain(msg.nameservers.iter_mut())
+	.chain(msg.additional.iter_mut());
+    for rr in records {
+	if rr.name.eq_ignore_ascii_case(sent) {
+	    rr.name = name.to_owned();
+	}
+    }
+    for q in msg.questions.iter_mut() {
+	q.name = name.to_owned();
+    }
+}
+
+fn upstream_query(q: &Question, name: &str, edns: bool) -> Message {
     let mut msg = Message::new();
     msg.id = genid() as u32;
     msg.qr = 0; // query
     msg.opcode = 0; // standard query
     msg.rd = 1; // recursive query
     msg.questions.push(message::Question{
-        name: name.to_owned(),
-        qtype: qtype,
-        class: dns::RecordClass::IN, // IN
+	name: name.to_owned(),
+	qtype: q.rtype,
+	class: q.class,
     });
-    let data = msg.into_bytes().expect("oops");
-    socket.send(&data).await.expect("oops");
-    Ok(())
+    if edns {
+	let mut e = message::Edns::new(EDNS_PAYLOAD_SIZE);
+	e.dnssec_ok = q.dnssec_ok;
+	msg.edns = Some(e);
+    }
+    msg
 }
 
-async fn upstream_reply_a(socket: &mut tokio::net::UdpSocket) -> Result<FwdrAnswer, std::io::Error> {
-    let mut buf = [0; 512];
-    let amt = match timeout(Duration::from_secs(2), socket.recv(&mut buf)).await {
-	Err(_) => {
-	    eprintln!("Upstream timeout");
-	    return Err(Error::new(ErrorKind::Other, "BitCursor overflow"));
-	},
-	Ok(amt) => amt?,
+// Error for an answer that only differs from the question in the case of
+// the name.
+#[derive(Debug)]
+struct CaseMismatch;
+
+impl std::fmt::Display for CaseMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	write!(f, "question case mismatch")
+    }
+}
+
+impl std::error::Error for CaseMismatch {}
+
+fn is_case_mismatch(e: &Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<CaseMismatch>())
+}
+
+// Checks that an upstream message answers our query, RFC 5452 section
+// 9.1. With match_case the name has to come back in exactly the case we
+// sent, some upstreams don't preserve it though.
+fn validate_response(query: &Message, msg: &view::MessageView, match_case: bool) -> Result<(), Error> {
+    let fail = |reason: &str| Err(Error::new(ErrorKind::InvalidData, reason.to_owned()));
+    let header = msg.header();
+    if header.qr != 1 {
+	return fail("not a response");
+    }
+    if header.id != query.id {
+	return fail("ID mismatch");
+    }
+    // Servers that can't parse the query may leave out the question.
+    if msg.question_count() == 0 && header.rcode == 1 {
+	return Ok(());
+    }
+    let q = match &query.questions[..] {
+	[q] if msg.question_count() == 1 => q,
+	_ => return fail("question count mismatch"),
     };
-    let msg = Message::from(&mut buf[..amt]).expect("oops");
-    println!("Upstream answer: {:?}", msg);
-    Ok(FwdrAnswer{rcode: msg.rcode, answers: msg.answers,
-		  nameservers: msg.nameservers, additional: msg.additional})
-}
-
-async fn handle_fwd(q: Question) -> Result<(), std::io::Error> {
-    let mut socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await.expect("oops");
-    socket.connect("9.9.9.9:53").await.expect("oops");
-    println!("Upstream query: {:?}, {:?}", q.name, q.rtype);
-    upstream_query_a(&mut socket, &q.name, q.rtype).await.expect("oops");
-    let answer = match upstream_reply_a(&mut socket).await {
-	Err(_) => {
-	    if let Err(_) = q.rsp_to.send(FwdrAnswer{
-		rcode: 5,
-		answers: Vec::new(),
-		nameservers: Vec::new(),
-		additional: Vec::new(),
-	    }).await {
-		eprintln!("handle_fwd: Failed to send answer");
-	    }
-	    return Ok(());
-	},
-	Ok(a) => a,
+    let r = match msg.questions().next() {
+	Some(Ok(r)) => r,
+	_ => return fail("malformed question"),
     };
-    if let Err(err) = q.rsp_to.send(answer).await {
-	eprintln!("handle_fwd: Failed to send answer: {:?}", err);
+    if !r.name.matches(&q.name, true) || u16::from(q.qtype) != u16::from(r.qtype)
+	|| u16::from(q.class) != u16::from(r.class) {
+	return fail("question mismatch");
+    }
+    if match_case && !r.name.matches(&q.name, false) {
+	return Err(Error::new(ErrorKind::InvalidData, CaseMismatch));
     }
     Ok(())
 }
 
-async fn forwarder(mut qs: tokio::sync::mpsc::Receiver<Question>) -> Result<(), std::io::Error> {
+async fn upstream_query_a(sockets: &socketpool::SocketPool, addr: SocketAddr, q: &Question, name: &str,
+			  edns: bool) -> Result<(Message, socketpool::Exchange), std::io::Error> {
+    let mut query = upstream_query(q, name, edns);
+    let exchange = sockets.send(addr, &mut query).await?;
+    Ok((query, exchange))
+}
+
+// Waits for the answer to query from addr. Other datagrams with its ID
+// that don't answer it are discarded, one that only changed the case of
+// the name fails the exchange so it can be retried without 0x20.
+async fn upstream_reply_a(exchange: &mut socketpool::Exchange, addr: SocketAddr, query: &Message,
+			  match_case: bool, wait: std::time::Duration) -> Result<Message, std::io::Error> {
+    let deadline = Instant::now() + wait;
+    loop {
+	let data = match tokio::time::timeout_at(deadline, exchange.recv()).await {
+	    Ok(Some(data)) => data,
+	    _ => {
+		return Err(Error::new(ErrorKind::TimedOut, "Upstream timeout"));
+	    },
+	};
+	// Only answers to the query are decoded, spoofed ones are dropped
+	// after looking at the header and question.
+	let result = view::MessageView::new(&data).map_err(Error::from)
+	    .and_then(|view| validate_response(query, &view, match_case))
+	    .and_then(|()| Ok(Message::from(&data)?));
+	match result {
+	    Ok(msg) => {
+		debug!("Upstream answer: {:?}", msg);
+		return Ok(msg);
+	    },
+	    Err(e) if is_case_mismatch(&e) => return Err(e),
+	    Err(e) => warn!("Discarding answer from {}: {}", addr, e),
+	}
+    }
+}
+
+async fn upstream_tcp_a(addr: SocketAddr, q: &Question, name: &str,
+			match_case: bool) -> Result<Message, std::io::Error> {
+    let mut stream = tokio::net::TcpStream::connect(addr).await?;
+    let mut query = upstream_query(q, name, true);
+    let data = query.into_bytes()?;
+    tcp::write_message(&mut stream, &data).await?;
+    let data = tcp::read_message(&mut stream).await?
+	.ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "Upstream closed TCP connection"))?;
+    validate_response(&query, &view::MessageView::new(&data)?, match_case)?;
+    let msg = Message::from(&data)?;
+    debug!("Upstream TCP answer: {:?}", msg);
+    Ok(msg)
+}
+
+async fn upstream_exchange(sockets: &socketpool::SocketPool, addr: SocketAddr, q: &Question,
+			   randomize: bool, wait: std::time::Duration) -> Result<Message, std::io::Error> {
+    let name = if randomize { randomize_case(&q.name) } else { q.name.clone() };
+    let (query, mut exchange) = upstream_query_a(sockets, addr, q, &name, true).await?;
+    let mut msg = upstream_reply_a(&mut exchange, addr, &query, randomize, wait).await?;
+    if msg.rcode == 1 && msg.edns.is_none() {
+	// FORMERR without OPT, the upstream doesn't speak EDNS (RFC 6891 section 7).
+	debug!("Upstream {} rejected EDNS, retrying without", addr);
+	let (query, mut exchange) = upstream_query_a(sockets, addr, q, &name, false).await?;
+	msg = upstream_reply_a(&mut exchange, addr, &query, randomize, wait).await?;
+    }
+    if msg.tc != 0 {
+	// Truncated, retry the same upstream over TCP.
+	debug!("Upstream {} truncated answer, retrying over TCP", addr);
+	msg = match timeout(wait, upstream_tcp_a(addr, q, &name, randomize)).await {
+	    Err(_) => return Err(Error::new(ErrorKind::TimedOut, "Upstream TCP timeout")),
+	    Ok(msg) => msg?,
+	};
+    }
+    restore_case(&mut msg, &name, &q.name);
+    Ok(msg)
+}
+
+// Sends the question to one upstream over its transport.
+async fn query_upstream(upstream: &upstream::Upstream, q: &Question, sockets: &socketpool::SocketPool,
+			wait: std::time::Duration) -> Result<Message, std::io::Error> {
+    if let upstream::Transport::Udp(addr) = &upstream.transport {
+	// Has its own timeouts for the UDP and TCP steps.
+	return match upstream_exchange(sockets, *addr, q, upstream.use_0x20(), wait).await {
+	    Err(e) if is_case_mismatch(&e) => {
+		debug!("Upstream {} changed the case of {:?}, retrying without 0x20", upstream.name, q.name);
+		let msg = upstream_exchange(sockets, *addr, q, false, wait).await?;
+		if upstream.set_mangles_case() {
+		    warn!("Upstream {} doesn't preserve the case of names, set randomize_case = false for it",
+			  upstream.name);
+		}
+		Ok(msg)
+	    },
+	    result => result,
+	};
+    }
+    // Encrypted transports can't be spoofed, the name is sent as is.
+    let mut query = upstream_query(q, &q.name, true);
+    let data = query.into_bytes()?;
+    let exchange = async {
+	let data = match &upstream.transport {
+	    upstream::Transport::Tls(client) => client.exchange(&data).await?,
+	    upstream::Transport::Https(client) => client.exchange(&data).await?,
+	    upstream::Transport::Udp(_) => unreachable!(),
+	};
+	validate_response(&query, &view::MessageView::new(&data)?, false)?;
+	let msg = Message::from(&data)?;
+	debug!("Upstream {} answer: {:?}", upstream.name, msg);
+	Ok(msg)
+    };
+    match timeout(wait, exchange).await {
+	Err(_) => Err(Error::new(ErrorKind::TimedOut, "Upstream timeout")),
+	Ok(msg) => msg,
+    }
+}
+
+type UpstreamResult = (usize, std::time::Duration, Result<Message, std::io::Error>);
+type Racer<'a> = Pin<Box<dyn Future<Output = UpstreamResult> + Send + 'a>>;
+
+async fn timed_query(upstreams: &UpstreamPool, i: usize, q: &Question,
+		     sockets: &socketpool::SocketPool) -> UpstreamResult {
+    let upstream = upstreams.get(i);
+    debug!("Upstream query: {:?}, {:?} -> {}", q.name, q.rtype, upstream.name);
+    let start = Instant::now();
+    let result = query_upstream(upstream, q, sockets, upstreams.timeout()).await;
+    (i, start.elapsed(), result)
+}
+
+// Waits for whichever racer finishes first and removes it.
+async fn next_finished(racers: &mut Vec<Racer<'_>>) -> UpstreamResult {
+    std::future::poll_fn(|cx| {
+	for k in 0..racers.len() {
+	    if let Poll::Ready(result) = racers[k].as_mut().poll(cx) {
+		drop(racers.swap_remove(k));
+		return Poll::Ready(result);
+	    }
+	}
+	Poll::Pending
+    }).await
+}
+
+// Tries the upstreams in the pool's order, race_width of them at a time.
+// The first answer other than SERVFAIL wins and the other queries are
+// dropped. A SERVFAIL is only returned if no upstream does better.
+async fn handle_fwd(q: &Question, upstreams: Arc<UpstreamPool>, sockets: &socketpool::SocketPool) -> FwdrAnswer {
+    let mut answer = None;
+    let order = upstreams.order();
+    'batches: for batch in order.chunks(upstreams.race_width()) {
+	let mut racers: Vec<Racer> = batch.iter()
+	    .map(|i| Box::pin(timed_query(&upstreams, *i, q, sockets)) as Racer)
+	    .collect();
+	while !racers.is_empty() {
+	    let (i, rtt, result) = next_finished(&mut racers).await;
+	    let upstream = upstreams.get(i);
+	    match result {
+		Ok(msg) => {
+		    upstreams.record_success(i, rtt);
+		    let servfail = msg.full_rcode() == 2;
+		    answer = Some(FwdrAnswer{rcode: msg.full_rcode(), answers: msg.answers,
+					     nameservers: msg.nameservers, additional: msg.additional});
+		    if !servfail {
+			if upstreams.race_width() > 1 {
+			    debug!("Upstream {} won the race in {:?}", upstream.name, rtt);
+			    upstreams.record_win(i);
+			}
+			break 'batches;
+		    }
+		},
+		Err(e) => {
+		    warn!("Upstream {} failed: {}", upstream.name, e);
+		    upstreams.record_failure(i);
+		},
+	    }
+	}
+    }
+    // Every upstream failed, answer SERVFAIL.
+    answer.unwrap_or(FwdrAnswer{
+	rcode: 2,
+	answers: Vec::new(),
+	nameservers: Vec::new(),
+	additional: Vec::new(),
+    })
+}
+
+// Sends each question upstream, unless the same one is already being
+// resolved. Then it just waits for that answer.
+async fn forwarder(mut qs: tokio::sync::mpsc::Receiver<Question>,
+		   upstreams: tokio::sync::watch::Receiver<Arc<UpstreamPool>>) -> Result<(), std::io::Error> {
+    let inflight = Arc::new(Mutex::new(HashMap::<InflightKey, Vec<tokio::sync::mpsc::Sender<FwdrAnswer>>>::new()));
+    let sockets = Arc::new(socketpool::SocketPool::new());
     loop {
 	tokio::select! {
 	    Some(q) = qs.recv() => {
+		let key = InflightKey::new(&q);
+		match inflight.lock().unwrap().entry(key.clone()) {
+		    Entry::Occupied(mut waiters) => {
+			debug!("Joining in-flight query: {:?}, {:?}", q.name, q.rtype);
+			waiters.get_mut().push(q.rsp_to);
+			continue;
+		    },
+		    Entry::Vacant(waiters) => {
+			waiters.insert(vec![q.rsp_to.clone()]);
+		    },
+		}
+		let upstreams = upstreams.borrow().clone();
+		let inflight = inflight.clone();
+		let sockets = sockets.clone();
 		tokio::spawn(async move {
-		    handle_fwd(q).await.expect("oops");
+		    let answer = handle_fwd(&q, upstreams, &sockets).await;
+		    let waiters = inflight.lock().unwrap().remove(&key).unwrap_or_default();
+		    for rsp_to in waiters {
+			if let Err(err) = rsp_to.send(answer.clone()).await {
+			    debug!("handle_fwd: Failed to send answer: {:?}", err);
+			}
+		    }
 		});
 	    }
 	}
     }
 }
 
-async fn udp_server(questions: tokio::sync::mpsc::Sender<(Vec<u8>, std::net::SocketAddr)>,
+async fn udp_server(server: tokio::net::UdpSocket,
+		    questions: tokio::sync::mpsc::Sender<(Vec<u8>, std::net::SocketAddr)>,
 		    mut answers: tokio::sync::mpsc::Receiver<(Vec<u8>, std::net::SocketAddr)>)
 		    -> Result<(), std::io::Error> {
-    let server = tokio::net::UdpSocket::bind("0.0.0.0:3553").await.expect("oops");
     loop {
-	let mut buf = [0; 512];
+	let mut buf = [0; EDNS_PAYLOAD_SIZE as usize];
 	tokio::select! {
 	    Ok((amt, source)) = server.recv_from(&mut buf) => {
 		questions.send((buf[0..amt].to_vec(), source)).await.expect("oops");
 	    },
 	    Some((data, source)) = answers.recv() => {
-		server.send_to(&data.to_vec(), source).await.expect("oops");
+		if let Err(e) = server.send_to(&data, source).await {
+		    warn!("UDP send to {} failed: {}", source, e);
+		}
 	    },
 	    else => {
 		panic!("oops");
@@ -159,144 +510,258 @@ async fn udp_server(questions: tokio::sync::mpsc::Sender<(Vec<u8>, std::net::Soc
     }
 }
 
-async fn handle_question(src: std::net::SocketAddr, message: Message,
-			 fwder: tokio::sync::mpsc::Sender<Question>,
-			 rsp_to: tokio::sync::mpsc::Sender<(Vec<u8>, std::net::SocketAddr)>) {
-    if message.questions.len() != 1 {
-	panic!("Only 1 query supported!");
-    }
-    println!("UDP Question: {:?}", message);
-    let name = &message.questions[0].name;
-    let (f_tx, mut f_rx) = tokio::sync::mpsc::channel::<FwdrAnswer>(1);
-    let fq = Question{
-	name: name.to_owned(),
-	rtype: message.questions[0].qtype,
-	rsp_to: f_tx,
-    };
-    fwder.send(fq).await.expect("oops");
-    if let Some(fa) = f_rx.recv().await {
-	let mut answer = create_response(&message, fa.rcode, &fa.answers,
-					 &fa.nameservers, &fa.additional);
-	println!("UDP Answer: {:?}", answer);
-	let data = answer.into_bytes().expect("oops");
-	rsp_to.send((data, src)).await.expect("oops");
-    }    
-}
-
-async fn handle_doh_question(req: Request<Body>, fwder: tokio::sync::mpsc::Sender<Question>) -> Result<Response<Body>, Infallible> {
-
-    let params: HashMap<String, String> = req.uri().query().map(|v| {
-	url::form_urlencoded::parse(v.as_bytes()).into_owned().collect()
-    }).expect("oops");
-    if matches!(req.method(), &Method::POST) {
-	panic!("oops");
-    }
-    let mut payload = match base64_url::decode(&params["dns"].to_owned()) {
-	Ok(payload) => payload,
-	_ => {
-	    eprintln!("oops");
-	    return Ok(Response::builder().status(500).body(Body::from("oops")).expect("oops"));
-	},
-    };
-    let message = Message::from(&mut payload).expect("oops");
-    println!("DoH Question: {:?}", message);
-    if let dns::RecordType::UNKNOWN(_) = message.questions[0].qtype {
-	let mut answer = create_response(&message, 4, &Vec::new(), &Vec::new(),
-					 &Vec::new());
-	println!("Not supported - DoH Answer: {:?}", answer);
-	let data = answer.into_bytes().expect("oops");
-	return Ok(Response::new(Body::from(data)));
-    }
-
-    let name = &message.questions[0].name;
-    let (f_tx, mut f_rx) = tokio::sync::mpsc::channel::<FwdrAnswer>(1);
-    let fq = Question{
-	name: name.to_owned(),
-	rtype: message.questions[0].qtype,
-	rsp_to: f_tx,
-    };
-    fwder.send(fq).await.expect("oops");
-    if let Some(fa) = f_rx.recv().await {
-	let mut answer = create_response(&message, fa.rcode, &fa.answers,
-					 &fa.nameservers, &fa.additional);
-	println!("DoH Answer: {:?}", answer);
-	let data = answer.into_bytes().expect("oops");
-	return Ok(Response::new(Body::from(data)));
-    }
-    eprintln!("DoH failed waiting for answer. Why?");
-    Ok(Response::builder().status(500).body(Body::from("oops")).expect("oops"))
-}
-
-fn run_doh(fwder: tokio::sync::mpsc::Sender<Question>) {
-    let addr = SocketAddr::from(([127, 0, 0, 1], 4443));
-    
-    let make_svc = make_service_fn(move |_conn: &AddrStream| {
-	let fwder = fwder.clone();
-        let service = service_fn(move |req| {
-	    handle_doh_question(req, fwder.clone())
-	});
-	async move {Ok::<_, Infallible>(service)}
-    });
-    
-    let server = Server::bind(&addr).serve(make_svc);
-    
-    tokio::spawn(async move {
-	if let Err(e) = server.await {
-            eprintln!("server error: {}", e);
+// Shared by all listeners, answers questions from the cache or through
+// the forwarder.
+struct Resolver {
+    fwder: tokio::sync::mpsc::Sender<Question>,
+    cache: Mutex<cache::Cache>,
+    hosts: tokio::sync::watch::Receiver<Arc<hosts::Hosts>>,
+    chaos: Mutex<config::ChaosConfig>,
+}
+
+impl Resolver {
+    fn new(fwder: tokio::sync::mpsc::Sender<Question>, cache_size: usize,
+	   hosts: tokio::sync::watch::Receiver<Arc<hosts::Hosts>>,
+	   chaos: config::ChaosConfig) -> Resolver {
+	Resolver{
+	    fwder,
+	    cache: Mutex::new(cache::Cache::new(cache_size)),
+	    hosts,
+	    chaos: Mutex::new(chaos),
 	}
-    });
+    }
 
+    async fn forward(&self, message: &Message) -> Option<FwdrAnswer> {
+	let (f_tx, mut f_rx) = tokio::sync::mpsc::channel::<FwdrAnswer>(1);
+	let fq = Question{
+	    name: message.questions[0].name.to_owned(),
+	    rtype: message.questions[0].qtype,
+	    class: message.questions[0].class,
+	    dnssec_ok: message.edns.as_ref().is_some_and(|e| e.dnssec_ok),
+	    rsp_to: f_tx,
+	};
+	self.fwder.send(fq).await.ok()?;
+	f_rx.recv().await
+    }
 
+    // Builds the response for the question, from the hosts file or the
+    // cache if possible. The message must be a query with one question.
+    async fn resolve(&self, message: &Message) -> Message {
+	if message.edns.as_ref().is_some_and(|e| e.version > 0) {
+	    // We only speak EDNS version 0.
+	    return error_response(message, 16); // BADVERS
+	}
+	let q = &message.questions[0];
+	if let dns::RecordClass::CH = q.class {
+	    return match chaos::lookup(&self.chaos.lock().unwrap(), &q.name, q.qtype) {
+		Some(answers) => create_response(message, 0, &answers, &[], &[]),
+		None => error_response(message, 5), // REFUSED
+	    };
+	}
+	let local = match q.class {
+	    dns::RecordClass::IN => self.hosts.borrow().lookup(&q.name, q.qtype),
+	    _ => None,
+	};
+	if let Some(answers) = local {
+	    debug!("Hosts answer: {:?}, {:?}", q.name, q.qtype);
+	    return create_response(message, 0, &answers, &Vec::new(), &Vec::new());
+	}
+	let dnssec_ok = message.edns.as_ref().is_some_and(|e| e.dnssec_ok);
+	let key = cache::CacheKey::new(&q.name, q.qtype, q.class, dnssec_ok);
+	let cached = self.cache.lock().unwrap().get(&key);
+	let fa = match cached {
+	    Some(fa) => {
+		debug!("Cache hit: {:?}, {:?}", q.name, q.qtype);
+		fa
+	    },
+	    None => {
+		let fa = match self.forward(message).await {
+		    Some(fa) => fa,
+		    None => {
+			error!("Forwarder did not answer {:?}", q.name);
+			return error_response(message, 2); // SERVFAIL
+		    },
+		};
+		self.cache.lock().unwrap().insert(key, &fa);
+		fa
+	    },
+	};
+	create_response(message, fa.rcode, &fa.answers,
+			&fa.nameservers, &fa.additional)
+    }
 }
 
-fn read_line(l: &str) {
-    // Remove comments
-    let parts: Vec<&str> = l.split("#").collect();
-    if parts[0].len() == 0 {
-	return;
-    }
-    // Split Addr/Name
-    let parts: Vec<&str> = parts[0].split(" ").collect();
-    let parts: Vec<&str> = parts.into_iter().filter(|w| w.len() != 0).collect();
-    if parts.len() < 2 {
-	return;
+async fn handle_question(src: std::net::SocketAddr, data: Vec<u8>,
+			 resolver: Arc<Resolver>,
+			 rsp_to: tokio::sync::mpsc::Sender<(Vec<u8>, std::net::SocketAddr)>) {
+    let (mut answer, limit) = match parse_query(&data) {
+	Ok(message) => {
+	    debug!("UDP Question: {:?}", message);
+	    let limit = match &message.edns {
+		Some(edns) => (edns.udp_payload_size.min(EDNS_PAYLOAD_SIZE) as usize).max(UDP_PAYLOAD_SIZE),
+		None => UDP_PAYLOAD_SIZE,
+	    };
+	    (resolver.resolve(&message).await, limit)
+	},
+	Err(Some(answer)) => (*answer, UDP_PAYLOAD_SIZE),
+	Err(None) => return,
+    };
+    debug!("UDP Answer: {:?}", answer);
+    let mut data = match encode_response(&mut answer) {
+	Some(data) => data,
+	None => return,
+    };
+    if data.len() > limit {
+	// Too big for UDP, the client has to retry over TCP.
+	answer.truncate();
+	data = match encode_response(&mut answer) {
+	    Some(data) => data,
+	    None => return,
+	};
     }
-    match parts[0].parse::<std::net::IpAddr>() {
-	Ok(addr) => println!("{:?}, {:?}", addr, parts[1]),
-	Err(e) => println!("{:?}, {:?}", parts[0], e),
+    if rsp_to.send((data, src)).await.is_err() {
+	error!("UDP server is gone, dropping answer to {}", src);
     }
 }
 
-fn read_hosts() {
-    let file = std::fs::File::open("hosts").expect("oops");
-    let lines = std::io::BufReader::new(file).lines();
-
-    for l in lines {
-	if let Ok(txt) = l {
-	    read_line(&txt);
+// Serves a TCP or DoT connection. Queries are resolved concurrently and
+// answered as soon as they are ready, possibly out of order (RFC 7766
+// section 6.2.1.1). Pending answers are still sent once the client goes
+// idle.
+async fn handle_tcp_conn<S>(stream: S, resolver: Arc<Resolver>) -> Result<(), std::io::Error>
+where S: AsyncRead + AsyncWrite + Send + 'static {
+    let (mut r, mut w) = tokio::io::split(stream);
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(MAX_PIPELINED);
+    let writer = tokio::spawn(async move {
+	while let Some(data) = rx.recv().await {
+	    tcp::write_message(&mut w, &data).await?;
 	}
+	w.shutdown().await
+    });
+    let inflight = Arc::new(tokio::sync::Semaphore::new(MAX_PIPELINED));
+    loop {
+	let permit = inflight.clone().acquire_owned().await.map_err(Error::other)?;
+	let data = match timeout(tcp::IDLE_TIMEOUT, tcp::read_message(&mut r)).await {
+	    Err(_) => break, // idle
+	    Ok(data) => match data? {
+		Some(data) => data,
+		None => break,
+	    },
+	};
+	let resolver = resolver.clone();
+	let tx = tx.clone();
+	tokio::spawn(async move {
+	    let mut answer = match parse_query(&data) {
+		Ok(message) => {
+		    debug!("TCP Question: {:?}", message);
+		    resolver.resolve(&message).await
+		},
+		Err(Some(answer)) => *answer,
+		Err(None) => return,
+	    };
+	    debug!("TCP Answer: {:?}", answer);
+	    if let Some(data) = encode_response(&mut answer) {
+		// Fails only if the writer is gone with the connection.
+		let _ = tx.send(data).await;
+	    }
+	    drop(permit);
+	});
+    }
+    drop(tx);
+    writer.await.map_err(Error::other)?
+}
+
+async fn tcp_server(listener: tokio::net::TcpListener,
+		    resolver: Arc<Resolver>) -> Result<(), std::io::Error> {
+    loop {
+	let (stream, src) = listener.accept().await?;
+	let resolver = resolver.clone();
+	tokio::spawn(async move {
+	    if let Err(e) = handle_tcp_conn(stream, resolver).await {
+		debug!("TCP connection from {} closed: {}", src, e);
+	    }
+	});
     }
 }
-    
+
+fn fatal(e: impl std::fmt::Display) -> ! {
+    eprintln!("dnsproxy: {}", e);
+    std::process::exit(1);
+}
+
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
-    read_hosts();
-    
-    //let mut cache = Cache::new();
+    let args = config::Args::parse();
+    let config = config::Config::from_args(args.clone()).unwrap_or_else(|e| fatal(e));
+    // The level is enforced through log::set_max_level so it can be
+    // changed on reload.
+    env_logger::Builder::new().filter_level(log::LevelFilter::Trace).init();
+    log::set_max_level(config.log_level());
+
+    let hosts = match &config.hosts {
+	Some(path) => {
+	    let (hosts, errors) = hosts::Hosts::load(path)
+		.unwrap_or_else(|e| fatal(format!("{}: {}", path.display(), e)));
+	    for e in errors {
+		warn!("{}", e);
+	    }
+	    hosts
+	},
+	None => hosts::Hosts::new(),
+    };
+    let (hosts_tx, hosts_rx) = tokio::sync::watch::channel(Arc::new(hosts));
+
+    let upstreams: Arc<UpstreamPool> = Arc::new(config.upstream.pool().unwrap_or_else(|e| fatal(e)));
+    let (upstreams_tx, upstreams_rx) = tokio::sync::watch::channel(upstreams);
+    let udp_socket = tokio::net::UdpSocket::bind(config.listen.udp).await
+	.unwrap_or_else(|e| fatal(format!("UDP listener {}: {}", config.listen.udp, e)));
+    info!("UDP listening on {}", config.listen.udp);
     let (udp_q_tx, mut udp_q_rx) = tokio::sync::mpsc::channel::<(Vec<u8>, std::net::SocketAddr)>(128);
     let (udp_r_tx, udp_r_rx) = tokio::sync::mpsc::channel::<(Vec<u8>, std::net::SocketAddr)>(128);
-    tokio::spawn(udp_server(udp_q_tx, udp_r_rx));
+    tokio::spawn(udp_server(udp_socket, udp_q_tx, udp_r_rx));
 
     let (fwd_q_tx, fwd_q_rx) = tokio::sync::mpsc::channel::<Question>(128);
-    tokio::spawn(forwarder(fwd_q_rx));
+    tokio::spawn(forwarder(fwd_q_rx, upstreams_rx));
+    let resolver = Arc::new(Resolver::new(fwd_q_tx, config.cache.size, hosts_rx, config.chaos.clone()));
 
-    run_doh(fwd_q_tx.clone());
+    let tcp_listener = tokio::net::TcpListener::bind(config.listen.tcp).await
+	.unwrap_or_else(|e| fatal(format!("TCP listener {}: {}", config.listen.tcp, e)));
+    info!("TCP listening on {}", config.listen.tcp);
+    tokio::spawn(tcp_server(tcp_listener, resolver.clone()));
+
+    let certs = match (&config.tls.cert, &config.tls.key) {
+	(Some(cert), Some(key)) => Some(tls::CertStore::new(cert, key).unwrap_or_else(|e| fatal(e))),
+	_ => None,
+    };
+    if let (Some(addr), Some(certs)) = (config.listen.dot, &certs) {
+	dot::run(addr, tls::server_config(certs.clone(), &dot::ALPN), resolver.clone()).await
+	    .unwrap_or_else(|e| fatal(format!("DoT listener {}: {}", addr, e)));
+    }
+    let doh_tls = certs.clone().map(|c| tls::server_config(c, &doh::ALPN));
+    doh::run(config.listen.doh, doh_tls, resolver.clone()).await
+	.unwrap_or_else(|e| fatal(format!("DoH listener {}: {}", config.listen.doh, e)));
+
+    let limiter = limiter::Limiter::new(config.limits.max_inflight,
+					config.limits.max_inflight_per_client);
+
+    tokio::spawn(reload::run(args, config, resolver.clone(), hosts_tx, upstreams_tx, certs));
 
     loop {
 	tokio::select!{
-	    Some((mut qdata, src)) = udp_q_rx.recv() => {
-		handle_question(src, Message::from(&mut qdata).expect("oops"),
-				fwd_q_tx.clone(), udp_r_tx.clone()).await;
+	    Some((qdata, src)) = udp_q_rx.recv() => {
+		let permit = match limiter.acquire(src.ip()).await {
+		    Some(permit) => permit,
+		    None => {
+			debug!("Too many questions in flight from {}, dropping", src.ip());
+			continue;
+		    },
+		};
+		let resolver = resolver.clone();
+		let udp_r_tx = udp_r_tx.clone();
+		tokio::spawn(async move {
+		    handle_question(src, qdata, resolver, udp_r_tx).await;
+		    drop(permit);
+		});
 	    }
 	    else => {
 		panic!("oops");
@@ -304,3 +769,295 @@ async fn main() -> Result<(), std::io::Error> {
 	}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Resolver whose forwarder answers every question with 1.2.3.4, names
+    // starting with "slow" after a delay.
+    fn resolver() -> Arc<Resolver> {
+	let (tx, mut rx) = tokio::sync::mpsc::channel::<Question>(8);
+	tokio::spawn(async move {
+	    while let Some(q) = rx.recv().await {
+		tokio::spawn(async move {
+		    if q.name.starts_with("slow") {
+			tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+		    }
+		    let answer = FwdrAnswer{
+			rcode: 0,
+			answers: vec![ResourceRecord{
+			    name: q.name.clone(),
+			    rtype: dns::RecordType::A,
+			    class: dns::RecordClass::IN,
+			    ttl: 60,
+			    data: dns::ResourceData::IPv4(std::net::Ipv4Addr::new(1, 2, 3, 4)),
+			}],
+			nameservers: Vec::new(),
+			additional: Vec::new(),
+		    };
+		    let _ = q.rsp_to.send(answer).await;
+		});
+	    }
+	});
+	let (_, hosts) = tokio::sync::watch::channel(Arc::new(hosts::Hosts::new()));
+	Arc::new(Resolver::new(tx, 16, hosts, config::ChaosConfig::default()))
+    }
+
+    fn query(id: u32, name: &str) -> Vec<u8> {
+	let mut m = Message::new();
+	m.id = id;
+	m.questions.push(message::Question{
+	    name: name.to_owned(),
+	    qtype: dns::RecordType::A,
+	    class: dns::RecordClass::IN,
+	});
+	m.into_bytes().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_pipelined() {
+	let (mut client, server) = tokio::io::duplex(4096);
+	tokio::spawn(handle_tcp_conn(server, resolver()));
+	tcp::write_message(&mut client, &query(1, "slow.example.")).await.unwrap();
+	tcp::write_message(&mut client, &query(2, "fast.example.")).await.unwrap();
+	tcp::write_message(&mut client, b"garbage").await.unwrap();
+	let mut ids = Vec::new();
+	for _ in 0..2 {
+	    let data = tcp::read_message(&mut client).await.unwrap().unwrap();
+	    let m = Message::from(&data).unwrap();
+	    assert_eq!(m.answers.len(), 1);
+	    ids.push(m.id);
+	}
+	assert_eq!(ids, vec![2, 1]);
+	client.shutdown().await.unwrap();
+	assert_eq!(tcp::read_message(&mut client).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_validate_response() {
+	let mut q = Message::new();
+	q.id = 7;
+	q.questions.push(message::Question{
+	    name: "ExAmple.com.".to_owned(),
+	    qtype: dns::RecordType::A,
+	    class: dns::RecordClass::IN,
+	});
+	let validate = |r: &mut Message, match_case| {
+	    let data = r.into_bytes().unwrap();
+	    validate_response(&q, &view::MessageView::new(&data).unwrap(), match_case)
+	};
+	let mut r = error_response(&q, 0);
+	assert!(validate(&mut r, true).is_ok());
+	r.id = 8;
+	assert!(validate(&mut r, true).is_err());
+	r.id = 7;
+	r.questions[0].name = "example.com.".to_owned();
+	assert!(is_case_mismatch(&validate(&mut r, true).unwrap_err()));
+	assert!(validate(&mut r, false).is_ok());
+	r.questions[0].name = "example.net.".to_owned();
+	assert!(validate(&mut r, false).is_err());
+	assert!(!is_case_mismatch(&validate(&mut r, true).unwrap_err()));
+	r.questions[0].name = "www.example.com.".to_owned();
+	assert!(validate(&mut r, false).is_err());
+	r.questions.clear();
+	assert!(validate(&mut r, true).is_err());
+	r.rcode = 1;
+	assert!(validate(&mut r, true).is_ok());
+	r.qr = 0;
+	assert!(validate(&mut r, true).is_err());
+    }
+
+    #[test]
+    fn test_parse_query() {
+	let data = query(3, "example.com.");
+	assert_eq!(parse_query(&data).unwrap().questions[0].name, "example.com.");
+	assert!(matches!(parse_query(&data[..5]), Err(None)));
+	let rcode = |data: &[u8]| parse_query(data).unwrap_err().unwrap().rcode;
+	// Truncated question.
+	assert_eq!(rcode(&data[..20]), 1);
+	let mut bad = data.clone();
+	bad[2] |= 0x80; // QR
+	assert!(matches!(parse_query(&bad), Err(None)));
+	bad[2] = 0x10; // opcode 2
+	assert_eq!(rcode(&bad), 4);
+	bad[2] = 0;
+	bad[5] = 2; // QDCOUNT
+	assert_eq!(rcode(&bad), 1);
+    }
+
+    #[test]
+    fn test_randomize_case() {
+	let name = "www.example-123.com.";
+	let names: Vec<String> = (0..16).map(|_| randomize_case(name)).collect();
+	assert!(names.iter().all(|n| n.eq_ignore_ascii_case(name)));
+	assert!(names.iter().any(|n| n != name));
+    }
+
+    // Spoofed answers with the wrong ID or question are skipped and the
+    // real one is still accepted.
+    #[tokio::test]
+    async fn test_upstream_discards_mismatched() {
+	let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+	let addr = server.local_addr().unwrap();
+	tokio::spawn(async move {
+	    let mut buf = [0; 512];
+	    let (amt, src) = server.recv_from(&mut buf).await.unwrap();
+	    let q = Message::from(&buf[..amt]).unwrap();
+	    let mut wrong_id = error_response(&q, 0);
+	    wrong_id.id ^= 1;
+	    let mut wrong_name = error_response(&q, 0);
+	    wrong_name.questions[0].name = "other.example.".to_owned();
+	    let mut right = error_response(&q, 3);
+	    for m in [&mut wrong_id, &mut wrong_name, &mut right] {
+		server.send_to(&m.into_bytes().unwrap(), src).await.unwrap();
+	    }
+	});
+	let (rsp_to, _) = tokio::sync::mpsc::channel(1);
+	let q = Question{name: "test.example.".to_owned(), rtype: dns::RecordType::A,
+			 class: dns::RecordClass::IN, dnssec_ok: false, rsp_to};
+	let sockets = socketpool::SocketPool::new();
+	let msg = upstream_exchange(&sockets, addr, &q, true, std::time::Duration::from_secs(2)).await.unwrap();
+	assert_eq!(msg.rcode, 3);
+	assert_eq!(msg.questions[0].name, "test.example.");
+    }
+
+    // An upstream that lowercases the name is asked again without 0x20,
+    // and from then on always without.
+    #[tokio::test]
+    async fn test_case_fallback() {
+	let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+	let addr = server.local_addr().unwrap();
+	let (names_tx, mut names_rx) = tokio::sync::mpsc::unbounded_channel();
+	tokio::spawn(async move {
+	    let mut buf = [0; 512];
+	    loop {
+		let (amt, src) = server.recv_from(&mut buf).await.unwrap();
+		let q = Message::from(&buf[..amt]).unwrap();
+		names_tx.send(q.questions[0].name.clone()).unwrap();
+		let mut r = error_response(&q, 3);
+		r.questions[0].name = q.questions[0].name.to_ascii_lowercase();
+		server.send_to(&r.into_bytes().unwrap(), src).await.unwrap();
+	    }
+	});
+	let (rsp_to, _) = tokio::sync::mpsc::channel(1);
+	let name = "CaSe.TeSt.ExAmPlE.";
+	let q = Question{name: name.to_owned(), rtype: dns::RecordType::A,
+			 class: dns::RecordClass::IN, dnssec_ok: false, rsp_to};
+	let sockets = socketpool::SocketPool::new();
+	let upstream = udp_upstream(addr);
+	let wait = std::time::Duration::from_secs(2);
+	let msg = query_upstream(&upstream, &q, &sockets, wait).await.unwrap();
+	assert_eq!(msg.rcode, 3);
+	assert!(!upstream.use_0x20());
+	let first = names_rx.recv().await.unwrap();
+	assert!(first.eq_ignore_ascii_case(name));
+	assert_eq!(names_rx.recv().await.unwrap(), name);
+	query_upstream(&upstream, &q, &sockets, wait).await.unwrap();
+	assert_eq!(names_rx.recv().await.unwrap(), name);
+    }
+
+    // UDP upstream answering every question with rcode after a delay. The
+    // receiver gets the names asked.
+    async fn fake_upstream(delay_ms: u64, rcode: u32) -> (SocketAddr, tokio::sync::mpsc::UnboundedReceiver<String>) {
+	let server = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+	let addr = server.local_addr().unwrap();
+	let (names_tx, names_rx) = tokio::sync::mpsc::unbounded_channel();
+	tokio::spawn(async move {
+	    let mut buf = [0; 512];
+	    loop {
+		let (amt, src) = server.recv_from(&mut buf).await.unwrap();
+		let q = Message::from(&buf[..amt]).unwrap();
+		let _ = names_tx.send(q.questions[0].name.to_ascii_lowercase());
+		let server = server.clone();
+		tokio::spawn(async move {
+		    tokio::time::sleep(std::time::Duration::from_millis(delay_ms)).await;
+		    server.send_to(&error_response(&q, rcode).into_bytes().unwrap(), src).await.unwrap();
+		});
+	    }
+	});
+	(addr, names_rx)
+    }
+
+    fn udp_upstream(addr: SocketAddr) -> upstream::Upstream {
+	upstream::Upstream::new(&addr.to_string(), upstream::Transport::Udp(addr))
+    }
+
+    // Concurrent identical questions share one upstream query, each
+    // answer keeps its own ID.
+    #[tokio::test]
+    async fn test_coalesce() {
+	let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+	let addr = server.local_addr().unwrap();
+	let (count_tx, mut count_rx) = tokio::sync::mpsc::unbounded_channel();
+	tokio::spawn(async move {
+	    let mut buf = [0; 512];
+	    loop {
+		let (amt, src) = server.recv_from(&mut buf).await.unwrap();
+		let q = Message::from(&buf[..amt]).unwrap();
+		count_tx.send(q.questions[0].name.to_ascii_lowercase()).unwrap();
+		tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+		server.send_to(&error_response(&q, 3).into_bytes().unwrap(), src).await.unwrap();
+	    }
+	});
+	let pool = UpstreamPool::new(vec![upstream::Upstream::new("test", upstream::Transport::Udp(addr))],
+				     upstream::Strategy::RoundRobin, std::time::Duration::from_secs(2));
+	let (_upstreams_tx, upstreams_rx) = tokio::sync::watch::channel(Arc::new(pool));
+	let (tx, rx) = tokio::sync::mpsc::channel(8);
+	tokio::spawn(forwarder(rx, upstreams_rx));
+	let (_, hosts) = tokio::sync::watch::channel(Arc::new(hosts::Hosts::new()));
+	let resolver = Arc::new(Resolver::new(tx, 0, hosts, config::ChaosConfig::default()));
+	let mut tasks = Vec::new();
+	for id in 0..4 {
+	    let resolver = resolver.clone();
+	    let name = if id % 2 == 0 { "same.example." } else { "SAME.example." };
+	    let data = query(id, name);
+	    tasks.push(tokio::spawn(async move {
+		resolver.resolve(&Message::from(&data).unwrap()).await
+	    }));
+	}
+	for (id, task) in tasks.into_iter().enumerate() {
+	    let answer = task.await.unwrap();
+	    assert_eq!(answer.id, id as u32);
+	    assert_eq!(answer.rcode, 3);
+	}
+	assert_eq!(count_rx.recv().await.unwrap(), "same.example.");
+	assert!(count_rx.try_recv().is_err());
+    }
+
+    // The fast SERVFAIL is passed over, the first real answer wins
+    // without waiting for the slow upstream.
+    #[tokio::test]
+    async fn test_race() {
+	let (servfail, _) = fake_upstream(0, 2).await;
+	let (fast, _) = fake_upstream(50, 3).await;
+	let (slow, _) = fake_upstream(2000, 0).await;
+	let mut pool = UpstreamPool::new(vec![udp_upstream(servfail), udp_upstream(fast), udp_upstream(slow)],
+					 upstream::Strategy::Race, std::time::Duration::from_secs(5));
+	pool.set_race_width(3);
+	let pool = Arc::new(pool);
+	let (rsp_to, _) = tokio::sync::mpsc::channel(1);
+	let q = Question{name: "race.example.".to_owned(), rtype: dns::RecordType::A,
+			 class: dns::RecordClass::IN, dnssec_ok: false, rsp_to};
+	let start = Instant::now();
+	let answer = handle_fwd(&q, pool.clone(), &socketpool::SocketPool::new()).await;
+	assert_eq!(answer.rcode, 3);
+	assert!(start.elapsed() < std::time::Duration::from_secs(1));
+	// The slow upstream wasn't measured, so it sorts first now.
+	assert_eq!(pool.order()[0], 2);
+    }
+
+    // Without racing a SERVFAIL still moves on to the next upstream.
+    #[tokio::test]
+    async fn test_servfail_failover() {
+	let (servfail, _) = fake_upstream(0, 2).await;
+	let (next, _) = fake_upstream(0, 3).await;
+	let pool = Arc::new(UpstreamPool::new(vec![udp_upstream(servfail), udp_upstream(next)],
+					      upstream::Strategy::RoundRobin, std::time::Duration::from_secs(5)));
+	let (rsp_to, _) = tokio::sync::mpsc::channel(1);
+	let q = Question{name: "failover.example.".to_owned(), rtype: dns::RecordType::A,
+			 class: dns::RecordClass::IN, dnssec_ok: false, rsp_to};
+	let answer = handle_fwd(&q, pool, &socketpool::SocketPool::new()).await;
+	assert_eq!(answer.rcode, 3);
+    }
+}
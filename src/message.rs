@@ -2,7 +2,7 @@ use byteorder::*;
 use std::io::Cursor;
 use std::io::Read;
 use std::io::Write;
-use std::io::{Error, ErrorKind};
+use std::io::ErrorKind;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
 
@@ -16,9 +16,69 @@ use std::net::Ipv6Addr;
 // 15 -> MX
 // 16 -> TXT
 // 28 -> AAAA 
+// 33 -> SRV
+// 35 -> NAPTR
+// 41 -> OPT (as Message::edns)
+// 43 -> DS
+// 44 -> SSHFP
+// 46 -> RRSIG
+// 47 -> NSEC
+// 48 -> DNSKEY
+// 50 -> NSEC3
+// 52 -> TLSA
+// 64 -> SVCB
+// 65 -> HTTPS
+// 257 -> CAA
+// Anything else is kept as opaque RDATA (RFC 3597).
 
 use crate::nametree;
 use crate::dns;
+use crate::view;
+
+// Why a message could not be parsed or written.
+#[derive(Debug)]
+pub enum MessageError {
+    // The data ended in the middle of the message.
+    Truncated,
+    // A field holds something the wire format does not allow.
+    Malformed(String),
+    // Well formed, but uses something we don't implement.
+    Unsupported(String),
+}
+
+impl std::fmt::Display for MessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	match self {
+	    MessageError::Truncated => write!(f, "truncated message"),
+	    MessageError::Malformed(msg) => write!(f, "malformed message: {}", msg),
+	    MessageError::Unsupported(msg) => write!(f, "unsupported: {}", msg),
+	}
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+impl From<std::io::Error> for MessageError {
+    fn from(e: std::io::Error) -> MessageError {
+	match e.kind() {
+	    ErrorKind::UnexpectedEof => MessageError::Truncated,
+	    _ => MessageError::Malformed(e.to_string()),
+	}
+    }
+}
+
+impl From<std::num::TryFromIntError> for MessageError {
+    fn from(_: std::num::TryFromIntError) -> MessageError {
+	MessageError::Malformed("field too large".to_owned())
+    }
+}
+
+// The upstream code works in std::io::Error.
+impl From<MessageError> for std::io::Error {
+    fn from(e: MessageError) -> std::io::Error {
+	std::io::Error::new(ErrorKind::InvalidData, e)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Message {
@@ -36,90 +96,80 @@ pub struct Message {
     pub answers: Vec<dns::ResourceRecord>,
     pub nameservers: Vec<dns::ResourceRecord>,
     pub additional: Vec<dns::ResourceRecord>,
+    pub edns: Option<Edns>,
 }
 
+// EDNS(0) OPT pseudo-record, RFC 6891.
 #[derive(Debug, Clone)]
-pub struct Question {
-    pub name: String,
-    pub qtype: dns::RecordType,
-    pub class: dns::RecordClass, 
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub extended_rcode: u8, // upper 8 bits of the 12 bit rcode
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
 }
 
-struct BitCursor {
-    value: u8,
-    cur: usize,
+#[derive(Debug, Clone)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
 }
 
-impl BitCursor {
-    fn new<T>(c: &mut std::io::Cursor<T>) -> Result<BitCursor, std::io::Error> where T: AsRef<[u8]> {
-        let mut value = [0; 1];
-        c.read_exact(&mut value)?;
-        return Ok(BitCursor{
-            value: value[0],
-            cur: 8,
-        });
-    }
-    
-    fn read(&mut self, bits: usize) -> Result<u8, std::io::Error> {
-        if bits > self.cur {
-            return Err(Error::new(ErrorKind::Other, "BitCursor overflow"));
-        }
-        let mask = (0x1 << bits) - 1;
-        let result = (self.value >> (self.cur - bits)) & mask;
-        self.cur -= bits;
-        return Ok(result);
+impl Edns {
+    pub fn new(udp_payload_size: u16) -> Edns {
+	Edns{
+	    udp_payload_size,
+	    extended_rcode: 0,
+	    version: 0,
+	    dnssec_ok: false,
+	    options: Vec::new(),
+	}
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct Question {
+    pub name: String,
+    pub qtype: dns::RecordType,
+    pub class: dns::RecordClass, 
+}
 
 struct MessageParser<'a> {
-    c: Cursor<&'a mut [u8]>,
-    nr: nametree::NameReader,
+    c: Cursor<&'a [u8]>,
 }
 
 impl MessageParser<'_> {
-    fn new<'a>(data: &'a mut [u8]) -> MessageParser<'a> {
-        MessageParser{
-            c: Cursor::new(data),
-            nr: nametree::NameReader::new(),
-        }
+    // Parser for the RDATA at pos, names in it may point anywhere
+    // before.
+    fn at(data: &[u8], pos: usize) -> MessageParser<'_> {
+	let mut c = Cursor::new(data);
+	c.set_position(pos as u64);
+	MessageParser{c}
     }
-    
-    fn parse_question(&mut self) -> Result<Question, std::io::Error> {
-        let name = self.nr.read(&mut self.c)?;
-        let qtype = dns::RecordType::try_from(self.c.read_u16::<BigEndian>()?)?;
-        let class = dns::RecordClass::try_from(self.c.read_u16::<BigEndian>()?)?;	
-        return Ok(Question{
-            name,
-            qtype,
-            class,
-        });
-    }
-    fn parse_questions(&mut self, count: u32) -> Result<Vec<Question>, std::io::Error> {
-        let mut qs = Vec::<Question>::new();
-        for _ in 0..count {
-            qs.push(self.parse_question()?);
-        }
-        return Ok(qs);
+
+    fn read_name(&mut self) -> Result<String, MessageError> {
+	let (name, end) = view::Name::parse(self.c.get_ref(), self.c.position() as usize)?;
+	self.c.set_position(end as u64);
+	Ok(name.to_string())
     }
-    
-    fn parse_ipv4(&mut self) -> Result<Ipv4Addr, std::io::Error> {
+
+    fn parse_ipv4(&mut self) -> Result<Ipv4Addr, MessageError> {
         let mut data: [u8; 4] = [0; 4];
         self.c.read_exact(&mut data)?;
         return Ok(Ipv4Addr::new(data[0], data[1], data[2], data[3]));
     }
 
-    fn parse_ns(&mut self) -> Result<String, std::io::Error> {
-	return Ok(self.nr.read(&mut self.c)?);
+    fn parse_ns(&mut self) -> Result<String, MessageError> {
+	return Ok(self.read_name()?);
     }
     
-    fn parse_cname(&mut self) -> Result<String, std::io::Error> {
-        return Ok(self.nr.read(&mut self.c)?);
+    fn parse_cname(&mut self) -> Result<String, MessageError> {
+	return Ok(self.read_name()?);
     }
 
-    fn parse_soa(&mut self) -> Result<dns::Soa, std::io::Error> {
-	let mname = self.nr.read(&mut self.c)?;
-	let rname = self.nr.read(&mut self.c)?;
+    fn parse_soa(&mut self) -> Result<dns::Soa, MessageError> {
+	let mname = self.read_name()?;
+	let rname = self.read_name()?;
 	let serial = self.c.read_u32::<BigEndian>()?;
 	let refresh = self.c.read_u32::<BigEndian>()?;
 	let retry = self.c.read_u32::<BigEndian>()?;
@@ -136,46 +186,44 @@ impl MessageParser<'_> {
 	})
     }
 
-    fn parse_ptr(&mut self) -> Result<String, std::io::Error> {
-        Ok(self.nr.read(&mut self.c)?)
+    fn parse_ptr(&mut self) -> Result<String, MessageError> {
+	Ok(self.read_name()?)
     }
 
-    fn parse_mx(&mut self) -> Result<dns::Mx, std::io::Error> {
+    fn parse_mx(&mut self) -> Result<dns::Mx, MessageError> {
 	let preference = self.c.read_u16::<BigEndian>()?;
-	let exchange = self.nr.read(&mut self.c)?;
+	let exchange = self.read_name()?;
 	Ok(dns::Mx{
 	    preference,
 	    exchange,
 	})
     }
 
-    fn parse_txt(&mut self) -> Result<String, std::io::Error> {
-	let len = self.c.read_u8()?;
-	let mut data = Vec::<u8>::new();
-	std::io::Read::by_ref(&mut self.c).take(len as u64).read_to_end(&mut data)?;
-	Ok(String::from_utf8_lossy(&data).to_string())
+    fn parse_txt(&mut self, end: u64) -> Result<Vec<Vec<u8>>, MessageError> {
+	let mut strings = Vec::new();
+	while self.c.position() < end {
+	    strings.push(self.read_string()?);
+	}
+	Ok(strings)
     }
     
-    fn parse_ipv6(&mut self) -> Result<Ipv6Addr, std::io::Error> {
+    fn parse_ipv6(&mut self) -> Result<Ipv6Addr, MessageError> {
         let mut data: [u8; 16] = [0; 16];
         self.c.read_exact(&mut data)?;
         Ok(Ipv6Addr::from(data))
     }
 
-    fn parse_https(&mut self, len: u64) -> Result<dns::Svcb, std::io::Error> {
-	println!("Total len: {:?}", len);
+    fn parse_https(&mut self, len: u64) -> Result<dns::Svcb, MessageError> {
 	let start = self.c.position();
 	let field_priority = self.c.read_u16::<BigEndian>()?;
-	let domain_name = self.nr.read(&mut self.c)?;
+	let domain_name = self.read_name()?;
 	if field_priority == 0 { // AliasForm
 	    return Ok(dns::Svcb{
 		domain_name,
 		form: dns::SvcbForm::ALIASFORM,
 	    });
 	}
-	let data_len = len - (self.c.position() - start);
-	let mut data = Vec::<u8>::new();
-	std::io::Read::by_ref(&mut self.c).take(data_len as u64).read_to_end(&mut data)?;
+	let mut data = self.read_rest(start + len)?;
 	let params = Self::parse_svcb_params(&mut data)?;
 	Ok(dns::Svcb{
 	    domain_name,
@@ -186,7 +234,7 @@ impl MessageParser<'_> {
 	})
     }
 
-    fn parse_svcb_params(data: &mut Vec<u8>) -> Result<Vec<dns::SvcbParam>, std::io::Error> {
+    fn parse_svcb_params(data: &mut Vec<u8>) -> Result<Vec<dns::SvcbParam>, MessageError> {
 	let end = data.len();
 	let mut c = Cursor::new(data);
 	let mut values = Vec::<dns::SvcbParam>::new();
@@ -203,13 +251,199 @@ impl MessageParser<'_> {
 	Ok(values)
     }
     
-    fn parse_unknown(&mut self, rtype: u16, len: u64) -> Result<u32, std::io::Error> {
-        let mut data = Vec::<u8>::new();	
-	std::io::Read::by_ref(&mut self.c).take(len as u64).read_to_end(&mut data)?;
-        Ok(rtype.into())
+    // Reads a length prefixed character-string.
+    fn read_string(&mut self) -> Result<Vec<u8>, MessageError> {
+	let len = self.c.read_u8()?;
+	let mut data = vec![0; len as usize];
+	self.c.read_exact(&mut data)?;
+	Ok(data)
+    }
+
+    // Reads the rest of the RDATA ending at end.
+    fn read_rest(&mut self, end: u64) -> Result<Vec<u8>, MessageError> {
+	let len = end.checked_sub(self.c.position())
+	    .ok_or_else(|| MessageError::Malformed("record overruns its length".to_owned()))?;
+	let mut data = vec![0; len as usize];
+	self.c.read_exact(&mut data)?;
+	Ok(data)
+    }
+
+    // Type bitmap of NSEC and NSEC3, RFC 4034 section 4.1.2.
+    fn read_types(&mut self, end: u64) -> Result<Vec<dns::RecordType>, MessageError> {
+	let mut types = Vec::new();
+	while self.c.position() < end {
+	    let window = self.c.read_u8()? as u16;
+	    let len = self.c.read_u8()?;
+	    if len == 0 || len > 32 {
+		return Err(MessageError::Malformed(format!("type bitmap length {}", len)));
+	    }
+	    for i in 0..len as u16 {
+		let bits = self.c.read_u8()?;
+		for bit in 0..8 {
+		    if bits & (0x80 >> bit) != 0 {
+			types.push(dns::RecordType::try_from(window << 8 | i << 3 | bit)?);
+		    }
+		}
+	    }
+	}
+	Ok(types)
+    }
+
+    fn parse_srv(&mut self) -> Result<dns::Srv, MessageError> {
+	let priority = self.c.read_u16::<BigEndian>()?;
+	let weight = self.c.read_u16::<BigEndian>()?;
+	let port = self.c.read_u16::<BigEndian>()?;
+	let target = self.read_name()?;
+	Ok(dns::Srv{
+	    priority,
+	    weight,
+	    port,
+	    target,
+	})
+    }
+
+    fn parse_naptr(&mut self) -> Result<dns::Naptr, MessageError> {
+	let order = self.c.read_u16::<BigEndian>()?;
+	let preference = self.c.read_u16::<BigEndian>()?;
+	let flags = self.read_string()?;
+	let services = self.read_string()?;
+	let regexp = self.read_string()?;
+	let replacement = self.read_name()?;
+	Ok(dns::Naptr{
+	    order,
+	    preference,
+	    flags,
+	    services,
+	    regexp,
+	    replacement,
+	})
+    }
+
+    fn parse_ds(&mut self, end: u64) -> Result<dns::Ds, MessageError> {
+	let key_tag = self.c.read_u16::<BigEndian>()?;
+	let algorithm = self.c.read_u8()?;
+	let digest_type = self.c.read_u8()?;
+	let digest = self.read_rest(end)?;
+	Ok(dns::Ds{
+	    key_tag,
+	    algorithm,
+	    digest_type,
+	    digest,
+	})
+    }
+
+    fn parse_sshfp(&mut self, end: u64) -> Result<dns::Sshfp, MessageError> {
+	let algorithm = self.c.read_u8()?;
+	let fp_type = self.c.read_u8()?;
+	let fingerprint = self.read_rest(end)?;
+	Ok(dns::Sshfp{
+	    algorithm,
+	    fp_type,
+	    fingerprint,
+	})
+    }
+
+    fn parse_rrsig(&mut self, end: u64) -> Result<dns::Rrsig, MessageError> {
+	let type_covered = dns::RecordType::try_from(self.c.read_u16::<BigEndian>()?)?;
+	let algorithm = self.c.read_u8()?;
+	let labels = self.c.read_u8()?;
+	let original_ttl = self.c.read_u32::<BigEndian>()?;
+	let expiration = self.c.read_u32::<BigEndian>()?;
+	let inception = self.c.read_u32::<BigEndian>()?;
+	let key_tag = self.c.read_u16::<BigEndian>()?;
+	let signer_name = self.read_name()?;
+	let signature = self.read_rest(end)?;
+	Ok(dns::Rrsig{
+	    type_covered,
+	    algorithm,
+	    labels,
+	    original_ttl,
+	    expiration,
+	    inception,
+	    key_tag,
+	    signer_name,
+	    signature,
+	})
+    }
+
+    fn parse_nsec(&mut self, end: u64) -> Result<dns::Nsec, MessageError> {
+	let next_domain = self.read_name()?;
+	let types = self.read_types(end)?;
+	Ok(dns::Nsec{
+	    next_domain,
+	    types,
+	})
+    }
+
+    fn parse_dnskey(&mut self, end: u64) -> Result<dns::Dnskey, MessageError> {
+	let flags = self.c.read_u16::<BigEndian>()?;
+	let protocol = self.c.read_u8()?;
+	let algorithm = self.c.read_u8()?;
+	let public_key = self.read_rest(end)?;
+	Ok(dns::Dnskey{
+	    flags,
+	    protocol,
+	    algorithm,
+	    public_key,
+	})
+    }
+
+    fn parse_nsec3(&mut self, end: u64) -> Result<dns::Nsec3, MessageError> {
+	let hash_algorithm = self.c.read_u8()?;
+	let flags = self.c.read_u8()?;
+	let iterations = self.c.read_u16::<BigEndian>()?;
+	let salt = self.read_string()?;
+	let next_hashed = self.read_string()?;
+	let types = self.read_types(end)?;
+	Ok(dns::Nsec3{
+	    hash_algorithm,
+	    flags,
+	    iterations,
+	    salt,
+	    next_hashed,
+	    types,
+	})
+    }
+
+    fn parse_tlsa(&mut self, end: u64) -> Result<dns::Tlsa, MessageError> {
+	let usage = self.c.read_u8()?;
+	let selector = self.c.read_u8()?;
+	let matching_type = self.c.read_u8()?;
+	let data = self.read_rest(end)?;
+	Ok(dns::Tlsa{
+	    usage,
+	    selector,
+	    matching_type,
+	    data,
+	})
+    }
+
+    fn parse_caa(&mut self, end: u64) -> Result<dns::Caa, MessageError> {
+	let flags = self.c.read_u8()?;
+	let tag = String::from_utf8_lossy(&self.read_string()?).to_string();
+	let value = self.read_rest(end)?;
+	Ok(dns::Caa{
+	    flags,
+	    tag,
+	    value,
+	})
+    }
+
+    fn parse_names(&mut self, number: bool, count: usize) -> Result<dns::Names, MessageError> {
+	let number = if number { Some(self.c.read_u16::<BigEndian>()?) } else { None };
+	let names = (0..count).map(|_| self.read_name()).collect::<Result<_, _>>()?;
+	Ok(dns::Names{
+	    number,
+	    names,
+	})
+    }
+
+    fn parse_unknown(&mut self, end: u64) -> Result<Vec<u8>, MessageError> {
+	self.read_rest(end)
     }
     
-    fn parse_rdata(&mut self, rtype: dns::RecordType, len: u64) -> Result<dns::ResourceData, std::io::Error> {
+    fn parse_rdata(&mut self, rtype: dns::RecordType, len: u64) -> Result<dns::ResourceData, MessageError> {
+	let end = self.c.position() + len;
         return match rtype {
             dns::RecordType::A => Ok(dns::ResourceData::IPv4(self.parse_ipv4()?)),
 	    dns::RecordType::NS => Ok(dns::ResourceData::Ns(self.parse_ns()?)),
@@ -217,76 +451,64 @@ impl MessageParser<'_> {
 	    dns::RecordType::SOA => Ok(dns::ResourceData::Soa(self.parse_soa()?)),
 	    dns::RecordType::PTR => Ok(dns::ResourceData::Ptr(self.parse_ptr()?)),
 	    dns::RecordType::MX => Ok(dns::ResourceData::Mx(self.parse_mx()?)),
-	    dns::RecordType::TXT => Ok(dns::ResourceData::Txt(self.parse_txt()?)),
+	    dns::RecordType::TXT => Ok(dns::ResourceData::Txt(self.parse_txt(end)?)),
             dns::RecordType::AAAA => Ok(dns::ResourceData::IPv6(self.parse_ipv6()?)),
+	    dns::RecordType::SRV => Ok(dns::ResourceData::Srv(self.parse_srv()?)),
+	    dns::RecordType::NAPTR => Ok(dns::ResourceData::Naptr(self.parse_naptr()?)),
+	    dns::RecordType::DS => Ok(dns::ResourceData::Ds(self.parse_ds(end)?)),
+	    dns::RecordType::SSHFP => Ok(dns::ResourceData::Sshfp(self.parse_sshfp(end)?)),
+	    dns::RecordType::RRSIG => Ok(dns::ResourceData::Rrsig(self.parse_rrsig(end)?)),
+	    dns::RecordType::NSEC => Ok(dns::ResourceData::Nsec(self.parse_nsec(end)?)),
+	    dns::RecordType::DNSKEY => Ok(dns::ResourceData::Dnskey(self.parse_dnskey(end)?)),
+	    dns::RecordType::NSEC3 => Ok(dns::ResourceData::Nsec3(self.parse_nsec3(end)?)),
+	    dns::RecordType::TLSA => Ok(dns::ResourceData::Tlsa(self.parse_tlsa(end)?)),
+	    // SVCB and HTTPS share the wire format, RFC 9460.
+	    dns::RecordType::SVCB => Ok(dns::ResourceData::Svcb(self.parse_https(len)?)),
 	    dns::RecordType::HTTPS => Ok(dns::ResourceData::Https(self.parse_https(len)?)),
-            _ => Ok(dns::ResourceData::Unimplemented(self.parse_unknown(u16::from(rtype), len)?)),
+	    dns::RecordType::CAA => Ok(dns::ResourceData::Caa(self.parse_caa(end)?)),
+	    dns::RecordType::MD | dns::RecordType::MF | dns::RecordType::MB |
+	    dns::RecordType::MG | dns::RecordType::MR => Ok(dns::ResourceData::Names(self.parse_names(false, 1)?)),
+	    dns::RecordType::MINFO | dns::RecordType::RP => Ok(dns::ResourceData::Names(self.parse_names(false, 2)?)),
+	    dns::RecordType::AFSDB | dns::RecordType::RT => Ok(dns::ResourceData::Names(self.parse_names(true, 1)?)),
+	    _ => Ok(dns::ResourceData::Unknown(self.parse_unknown(end)?)),
         };
     }
     
-    fn parse_resource(&mut self) -> Result<dns::ResourceRecord, std::io::Error> {
-        let name = self.nr.read(&mut self.c)?;
-        let rtype = dns::RecordType::try_from(self.c.read_u16::<BigEndian>()?)?;
-	let _ = self.c.read_u16::<BigEndian>()?; // OPT overloads this, and pisses me off.
-        let class = dns::RecordClass::IN;
-        let ttl = self.c.read_u32::<BigEndian>()? as u32;
-        let rdlen = self.c.read_u16::<BigEndian>()? as u64;
-        return Ok(dns::ResourceRecord{
-            name: name,
-            rtype: rtype,
-            class: class,
-            ttl: ttl,
-            data: self.parse_rdata(rtype, rdlen)?,
-        });
-    }
-    
-    fn parse_resources(&mut self, count: u32) -> Result<Vec<dns::ResourceRecord>, std::io::Error> {
-        let mut rs = Vec::<dns::ResourceRecord>::new();
-        for _ in 0..count {
-            rs.push(self.parse_resource()?);
-        }
-        return Ok(rs);
+    // OPT overloads class as the payload size and ttl as the extended
+    // rcode, version and flags.
+    fn parse_opt(&mut self, payload_size: u16, ttl: u32, len: u64) -> Result<Edns, MessageError> {
+	let end = self.c.position() + len;
+	let mut options = Vec::<EdnsOption>::new();
+	while self.c.position() < end {
+	    let code = self.c.read_u16::<BigEndian>()?;
+	    let option_len = self.c.read_u16::<BigEndian>()?;
+	    let mut data = Vec::<u8>::new();
+	    std::io::Read::by_ref(&mut self.c).take(option_len as u64).read_to_end(&mut data)?;
+	    if data.len() != option_len as usize {
+		return Err(MessageError::Truncated);
+	    }
+	    options.push(EdnsOption{code, data});
+	}
+	Ok(Edns{
+	    udp_payload_size: payload_size,
+	    extended_rcode: (ttl >> 24) as u8,
+	    version: (ttl >> 16) as u8,
+	    dnssec_ok: ttl & 0x8000 != 0,
+	    options,
+	})
     }
-    
-    fn parse(&mut self) -> Result<Message, std::io::Error> {
-        let id = self.c.read_u16::<BigEndian>()? as u32;
-        let mut flags = BitCursor::new(&mut self.c)?;
-        let qr = flags.read(1)?;
-        let opcode = flags.read(4)? as u32;
-        let aa = flags.read(1)?;
-        let tc = flags.read(1)?;
-        let rd = flags.read(1)?;
-        let mut flags = BitCursor::new(&mut self.c)?;
-        let ra = flags.read(1)?;
-        let _z = flags.read(1)?;
-        let ad = flags.read(1)?;
-        let cd = flags.read(1)?;
-        let rcode = flags.read(4)? as u32;
-        let qcount = self.c.read_u16::<BigEndian>()? as u32;
-        let ancount = self.c.read_u16::<BigEndian>()? as u32;
-        let nscount = self.c.read_u16::<BigEndian>()? as u32;
-        let arcount = self.c.read_u16::<BigEndian>()? as u32;
-        let questions = self.parse_questions(qcount)?;
-        let answers = self.parse_resources(ancount)?;
-        let nameservers = self.parse_resources(nscount)?;
-        let additional = self.parse_resources(arcount)?;
-        return Ok(Message{
-            id: id,
-            qr: qr,
-            opcode: opcode,
-            aa: aa,
-            tc: tc,
-            rd: rd,
-            ra: ra,
-            ad: ad,
-            cd: cd,
-            rcode: rcode,
-            questions: questions,
-            answers: answers,
-            nameservers: nameservers,
-            additional: additional,
-        });
+}
+
+// Typed RDATA of a record whose RDATA of len bytes starts at pos.
+pub fn parse_rdata(data: &[u8], pos: usize, rtype: dns::RecordType, len: usize) -> Result<dns::ResourceData, MessageError> {
+    let mut p = MessageParser::at(data, pos);
+    let rdata = p.parse_rdata(rtype, len as u64)?;
+    // The parsers read what the type defines, anything left over is
+    // skipped but reading past the end means rdlen was wrong.
+    if p.c.position() > (pos + len) as u64 {
+	return Err(MessageError::Malformed(format!("{:?} record overruns its length", rtype)));
     }
+    Ok(rdata)
 }
 
 struct MessageWriter<'a> {
@@ -304,27 +526,27 @@ impl<'a> MessageWriter<'_> {
         }
     }
 
-    pub fn write_a(&mut self, addr: &Ipv4Addr) -> Result<(), std::io::Error> {
-	self.c.write_u16::<BigEndian>(4 as u16).expect("oops");
-        self.c.write_all(&addr.octets()).expect("oops");
+    pub fn write_a(&mut self, addr: &Ipv4Addr) -> Result<(), MessageError> {
+	self.c.write_u16::<BigEndian>(4 as u16)?;
+	self.c.write_all(&addr.octets())?;
 	Ok(())
     }
 
-    pub fn write_ns(&mut self, name: &String) -> Result<(), std::io::Error> {
+    pub fn write_ns(&mut self, name: &String) -> Result<(), MessageError> {
 	let size = self.nw.size_of(&name);
-	self.c.write_u16::<BigEndian>(size.try_into().unwrap())?;
+	self.c.write_u16::<BigEndian>(size.try_into()?)?;
 	self.nw.write(&mut self.c, name)?;
 	Ok(())
     }
 
-    pub fn write_cname(&mut self, name: &String) -> Result<(), std::io::Error> {
+    pub fn write_cname(&mut self, name: &String) -> Result<(), MessageError> {
 	let size = self.nw.size_of(&name);
-	self.c.write_u16::<BigEndian>(size.try_into().unwrap())?;
+	self.c.write_u16::<BigEndian>(size.try_into()?)?;
 	self.nw.write(&mut self.c, name)?;
 	Ok(())
     } 
 
-    pub fn write_soa(&mut self, soa: &dns::Soa) -> Result<(), std::io::Error> {
+    pub fn write_soa(&mut self, soa: &dns::Soa) -> Result<(), MessageError> {
 	let len_pos = self.c.position();
 	self.c.write_u16::<BigEndian>(0)?;
 	let mut size = self.nw.size_of(&soa.mname);
@@ -340,129 +562,300 @@ impl<'a> MessageWriter<'_> {
 	// back write the size
 	let end_pos = self.c.position();
 	self.c.set_position(len_pos);
-	self.c.write_u16::<BigEndian>(size.try_into().unwrap())?;
+	self.c.write_u16::<BigEndian>(size.try_into()?)?;
 	self.c.set_position(end_pos);
 	Ok(())
     }
 
-    pub fn write_ptr(&mut self, name: &String) -> Result<(), std::io::Error> {
+    pub fn write_ptr(&mut self, name: &String) -> Result<(), MessageError> {
 	let size = self.nw.size_of(name);
-	self.c.write_u16::<BigEndian>(size.try_into().unwrap())?;
+	self.c.write_u16::<BigEndian>(size.try_into()?)?;
 	self.nw.write(&mut self.c, name)?;
 	Ok(())
     }
 
-    pub fn write_mx(&mut self, mx: &dns::Mx) -> Result<(), std::io::Error> {
+    pub fn write_mx(&mut self, mx: &dns::Mx) -> Result<(), MessageError> {
 	let size = self.nw.size_of(&mx.exchange) + 2;
-	self.c.write_u16::<BigEndian>(size.try_into().unwrap())?;
+	self.c.write_u16::<BigEndian>(size.try_into()?)?;
 	self.c.write_u16::<BigEndian>(mx.preference)?;
 	self.nw.write(&mut self.c, &mx.exchange)?;
 	Ok(())
     }
 
-    pub fn write_txt(&mut self, txt: &String) -> Result<(), std::io::Error> {
-	let size = txt.len() + 1;
-	self.c.write_u16::<BigEndian>(size.try_into().unwrap())?;
-	self.c.write_u8(txt.len().try_into().unwrap())?;
-	self.c.write_all(&txt.as_bytes())?;
+    pub fn write_txt(&mut self, strings: &[Vec<u8>]) -> Result<(), MessageError> {
+	let size: usize = strings.iter().map(|s| s.len() + 1).sum();
+	self.c.write_u16::<BigEndian>(size.try_into()?)?;
+	for s in strings {
+	    self.write_string(s)?;
+	}
 	Ok(())
     }
     
-    pub fn write_aaaa(&mut self, addr: &Ipv6Addr) -> Result<(), std::io::Error> {
-	self.c.write_u16::<BigEndian>(16 as u16).expect("oops");
-	self.c.write_all(&addr.octets()).expect("oops");
+    pub fn write_aaaa(&mut self, addr: &Ipv6Addr) -> Result<(), MessageError> {
+	self.c.write_u16::<BigEndian>(16 as u16)?;
+	self.c.write_all(&addr.octets())?;
 	Ok(())
     }
 
-    pub fn write_https(&mut self, https: &dns::Svcb) -> Result<(), std::io::Error> {
+    pub fn write_https(&mut self, https: &dns::Svcb) -> Result<(), MessageError> {
 	let len_pos = self.c.position();
 	self.c.write_u16::<BigEndian>(0)?;
 	match &https.form {
 	    dns::SvcbForm::ALIASFORM => {
 		self.c.write_u16::<BigEndian>(0)?; // priority = 0 for alias
-		self.nw.write(&mut self.c, &https.domain_name)?;
+		self.nw.write_uncompressed(&mut self.c, &https.domain_name)?;
 	    },
 	    dns::SvcbForm::SERVICEFORM(form) => {
 		self.c.write_u16::<BigEndian>(form.field_priority)?;
-		self.nw.write(&mut self.c, &https.domain_name)?;
+		self.nw.write_uncompressed(&mut self.c, &https.domain_name)?;
 		for p in &form.params {
 		    self.c.write_u16::<BigEndian>(p.key.into())?;
-		    self.c.write_u16::<BigEndian>(p.value.len().try_into().expect("oops"))?;
+		    self.c.write_u16::<BigEndian>(p.value.len().try_into()?)?;
 		    self.c.write_all(&p.value)?;
 		}
 	    },
 	}
-	// back write the size
+	self.write_len(len_pos)
+    }
+
+    // Back writes the RDATA length at len_pos, for types whose size is
+    // only known once written.
+    fn write_len(&mut self, len_pos: u64) -> Result<(), MessageError> {
 	let end_pos = self.c.position();
 	let size = end_pos - len_pos - 2; // -2 is for the len
 	self.c.set_position(len_pos);
-	self.c.write_u16::<BigEndian>(size.try_into().unwrap())?;
+	self.c.write_u16::<BigEndian>(size.try_into()?)?;
 	self.c.set_position(end_pos);
-	println!("Size: {:?}", size); 
 	Ok(())
     }
+
+    fn write_string(&mut self, data: &[u8]) -> Result<(), MessageError> {
+	self.c.write_u8(data.len().try_into()?)?;
+	self.c.write_all(data)?;
+	Ok(())
+    }
+
+    // Type bitmap of NSEC and NSEC3, one block per window of 256 types.
+    fn write_types(&mut self, types: &[dns::RecordType]) -> Result<(), MessageError> {
+	let mut types: Vec<u16> = types.iter().map(|t| u16::from(*t)).collect();
+	types.sort_unstable();
+	types.dedup();
+	for window in types.chunk_by(|a, b| a >> 8 == b >> 8) {
+	    let mut bits = [0u8; 32];
+	    for t in window {
+		bits[(t & 0xff) as usize >> 3] |= 0x80 >> (t & 7);
+	    }
+	    let len = (window[window.len() - 1] & 0xff) as usize / 8 + 1;
+	    self.c.write_u8((window[0] >> 8) as u8)?;
+	    self.c.write_u8(len as u8)?;
+	    self.c.write_all(&bits[..len])?;
+	}
+	Ok(())
+    }
+
+    // Names in the RDATA of the types below are never compressed, RFC
+    // 3597 section 4 and RFC 4034 section 6.2.
+    pub fn write_srv(&mut self, srv: &dns::Srv) -> Result<(), MessageError> {
+	let len_pos = self.c.position();
+	self.c.write_u16::<BigEndian>(0)?;
+	self.c.write_u16::<BigEndian>(srv.priority)?;
+	self.c.write_u16::<BigEndian>(srv.weight)?;
+	self.c.write_u16::<BigEndian>(srv.port)?;
+	self.nw.write_uncompressed(&mut self.c, &srv.target)?;
+	self.write_len(len_pos)
+    }
+
+    pub fn write_naptr(&mut self, naptr: &dns::Naptr) -> Result<(), MessageError> {
+	let len_pos = self.c.position();
+	self.c.write_u16::<BigEndian>(0)?;
+	self.c.write_u16::<BigEndian>(naptr.order)?;
+	self.c.write_u16::<BigEndian>(naptr.preference)?;
+	self.write_string(&naptr.flags)?;
+	self.write_string(&naptr.services)?;
+	self.write_string(&naptr.regexp)?;
+	self.nw.write_uncompressed(&mut self.c, &naptr.replacement)?;
+	self.write_len(len_pos)
+    }
+
+    pub fn write_ds(&mut self, ds: &dns::Ds) -> Result<(), MessageError> {
+	self.c.write_u16::<BigEndian>((ds.digest.len() + 4).try_into()?)?;
+	self.c.write_u16::<BigEndian>(ds.key_tag)?;
+	self.c.write_u8(ds.algorithm)?;
+	self.c.write_u8(ds.digest_type)?;
+	self.c.write_all(&ds.digest)?;
+	Ok(())
+    }
+
+    pub fn write_sshfp(&mut self, sshfp: &dns::Sshfp) -> Result<(), MessageError> {
+	self.c.write_u16::<BigEndian>((sshfp.fingerprint.len() + 2).try_into()?)?;
+	self.c.write_u8(sshfp.algorithm)?;
+	self.c.write_u8(sshfp.fp_type)?;
+	self.c.write_all(&sshfp.fingerprint)?;
+	Ok(())
+    }
+
+    pub fn write_rrsig(&mut self, sig: &dns::Rrsig) -> Result<(), MessageError> {
+	let len_pos = self.c.position();
+	self.c.write_u16::<BigEndian>(0)?;
+	self.c.write_u16::<BigEndian>(sig.type_covered.into())?;
+	self.c.write_u8(sig.algorithm)?;
+	self.c.write_u8(sig.labels)?;
+	self.c.write_u32::<BigEndian>(sig.original_ttl)?;
+	self.c.write_u32::<BigEndian>(sig.expiration)?;
+	self.c.write_u32::<BigEndian>(sig.inception)?;
+	self.c.write_u16::<BigEndian>(sig.key_tag)?;
+	self.nw.write_uncompressed(&mut self.c, &sig.signer_name)?;
+	self.c.write_all(&sig.signature)?;
+	self.write_len(len_pos)
+    }
+
+    pub fn write_nsec(&mut self, nsec: &dns::Nsec) -> Result<(), MessageError> {
+	let len_pos = self.c.position();
+	self.c.write_u16::<BigEndian>(0)?;
+	self.nw.write_uncompressed(&mut self.c, &nsec.next_domain)?;
+	self.write_types(&nsec.types)?;
+	self.write_len(len_pos)
+    }
+
+    pub fn write_dnskey(&mut self, key: &dns::Dnskey) -> Result<(), MessageError> {
+	self.c.write_u16::<BigEndian>((key.public_key.len() + 4).try_into()?)?;
+	self.c.write_u16::<BigEndian>(key.flags)?;
+	self.c.write_u8(key.protocol)?;
+	self.c.write_u8(key.algorithm)?;
+	self.c.write_all(&key.public_key)?;
+	Ok(())
+    }
+
+    pub fn write_nsec3(&mut self, nsec3: &dns::Nsec3) -> Result<(), MessageError> {
+	let len_pos = self.c.position();
+	self.c.write_u16::<BigEndian>(0)?;
+	self.c.write_u8(nsec3.hash_algorithm)?;
+	self.c.write_u8(nsec3.flags)?;
+	self.c.write_u16::<BigEndian>(nsec3.iterations)?;
+	self.write_string(&nsec3.salt)?;
+	self.write_string(&nsec3.next_hashed)?;
+	self.write_types(&nsec3.types)?;
+	self.write_len(len_pos)
+    }
+
+    pub fn write_tlsa(&mut self, tlsa: &dns::Tlsa) -> Result<(), MessageError> {
+	self.c.write_u16::<BigEndian>((tlsa.data.len() + 3).try_into()?)?;
+	self.c.write_u8(tlsa.usage)?;
+	self.c.write_u8(tlsa.selector)?;
+	self.c.write_u8(tlsa.matching_type)?;
+	self.c.write_all(&tlsa.data)?;
+	Ok(())
+    }
+
+    pub fn write_caa(&mut self, caa: &dns::Caa) -> Result<(), MessageError> {
+	let len_pos = self.c.position();
+	self.c.write_u16::<BigEndian>(0)?;
+	self.c.write_u8(caa.flags)?;
+	self.write_string(caa.tag.as_bytes())?;
+	self.c.write_all(&caa.value)?;
+	self.write_len(len_pos)
+    }
     
-    pub fn into_bytes(&mut self) -> Result<(), std::io::Error> {
-        self.c.write_u16::<BigEndian>(self.m.id as u16).expect("oops");
+    pub fn write_names(&mut self, names: &dns::Names) -> Result<(), MessageError> {
+	let len_pos = self.c.position();
+	self.c.write_u16::<BigEndian>(0)?;
+	if let Some(n) = names.number {
+	    self.c.write_u16::<BigEndian>(n)?;
+	}
+	for name in &names.names {
+	    self.nw.write_uncompressed(&mut self.c, name)?;
+	}
+	self.write_len(len_pos)
+    }
+
+    pub fn write_unknown(&mut self, data: &[u8]) -> Result<(), MessageError> {
+	self.c.write_u16::<BigEndian>(data.len().try_into()?)?;
+	self.c.write_all(data)?;
+	Ok(())
+    }
+
+    fn write_resource(&mut self, a: &dns::ResourceRecord) -> Result<(), MessageError> {
+	self.nw.write(&mut self.c, &a.name)?;
+	self.c.write_u16::<BigEndian>(u16::from(a.rtype))?;
+	self.c.write_u16::<BigEndian>(u16::from(a.class))?;
+	self.c.write_u32::<BigEndian>(a.ttl)?;
+	match &a.data {
+	    dns::ResourceData::IPv4(addr) => self.write_a(addr)?,
+	    dns::ResourceData::Ns(name) => self.write_ns(name)?,
+	    dns::ResourceData::CName(name) => self.write_cname(name)?,
+	    dns::ResourceData::Soa(soa) => self.write_soa(soa)?,
+	    dns::ResourceData::Ptr(name) => self.write_ptr(name)?,
+	    dns::ResourceData::Mx(mx) => self.write_mx(mx)?,
+	    dns::ResourceData::Txt(txt) => self.write_txt(txt)?,
+	    dns::ResourceData::IPv6(addr) => self.write_aaaa(addr)?,
+	    dns::ResourceData::Srv(srv) => self.write_srv(srv)?,
+	    dns::ResourceData::Naptr(naptr) => self.write_naptr(naptr)?,
+	    dns::ResourceData::Ds(ds) => self.write_ds(ds)?,
+	    dns::ResourceData::Sshfp(sshfp) => self.write_sshfp(sshfp)?,
+	    dns::ResourceData::Rrsig(sig) => self.write_rrsig(sig)?,
+	    dns::ResourceData::Nsec(nsec) => self.write_nsec(nsec)?,
+	    dns::ResourceData::Dnskey(key) => self.write_dnskey(key)?,
+	    dns::ResourceData::Nsec3(nsec3) => self.write_nsec3(nsec3)?,
+	    dns::ResourceData::Tlsa(tlsa) => self.write_tlsa(tlsa)?,
+	    dns::ResourceData::Svcb(svcb) | dns::ResourceData::Https(svcb) => self.write_https(svcb)?,
+	    dns::ResourceData::Caa(caa) => self.write_caa(caa)?,
+	    dns::ResourceData::Names(names) => self.write_names(names)?,
+	    dns::ResourceData::Unknown(data) => self.write_unknown(data)?,
+	}
+	Ok(())
+    }
+
+    // Message::rcode may hold a full 12 bit rcode, the header only
+    // carries the low 4 bits and the rest goes here.
+    fn write_opt(&mut self, edns: &Edns) -> Result<(), MessageError> {
+	let extended_rcode = (self.m.rcode >> 4) as u8 | edns.extended_rcode;
+	self.c.write_u8(0)?; // root
+	self.c.write_u16::<BigEndian>(u16::from(dns::RecordType::OPT))?;
+	self.c.write_u16::<BigEndian>(edns.udp_payload_size)?;
+	self.c.write_u8(extended_rcode)?;
+	self.c.write_u8(edns.version)?;
+	self.c.write_u16::<BigEndian>(if edns.dnssec_ok { 0x8000 } else { 0 })?;
+	let size: usize = edns.options.iter().map(|o| o.data.len() + 4).sum();
+	self.c.write_u16::<BigEndian>(size.try_into()?)?;
+	for o in &edns.options {
+	    self.c.write_u16::<BigEndian>(o.code)?;
+	    self.c.write_u16::<BigEndian>(o.data.len().try_into()?)?;
+	    self.c.write_all(&o.data)?;
+	}
+	Ok(())
+    }
+
+    pub fn into_bytes(&mut self) -> Result<(), MessageError> {
+	let m = self.m;
+	self.c.write_u16::<BigEndian>(m.id as u16)?;
         let mut flags = [0u8; 2];
         flags[0] = 
-            (self.m.qr & 0b1) << 7 |
-            (self.m.opcode as u8 & 0b1111) << 3 |
-            (self.m.aa & 0b1) << 2 |
-            (self.m.tc & 0b1) << 1 |
-            (self.m.rd & 0b1) << 0;
+	    (m.qr & 0b1) << 7 |
+	    (m.opcode as u8 & 0b1111) << 3 |
+	    (m.aa & 0b1) << 2 |
+	    (m.tc & 0b1) << 1 |
+	    (m.rd & 0b1);
         flags[1] = 
-            (self.m.ra & 0b1) << 7 |
-            (0 & 0b1) << 6 |
-            (self.m.ad & 0b1) << 5 |
-            (self.m.cd & 0b1) << 4 |
-            (self.m.rcode as u8 & 0b111) << 0;
-        self.c.write_all(&flags).expect("oops");
-        self.c.write_u16::<BigEndian>(self.m.questions.len() as u16).expect("oops");
-        self.c.write_u16::<BigEndian>(self.m.answers.len() as u16).expect("oops"); // an
-        self.c.write_u16::<BigEndian>(self.m.nameservers.len() as u16).expect("oops"); // ns
-        self.c.write_u16::<BigEndian>(self.m.additional.len() as u16).expect("oops"); // ad
-        //Self::write_query(&mut c, &self.questions);
-        for q in &self.m.questions {
+	    (m.ra & 0b1) << 7 |
+	    (m.ad & 0b1) << 5 |
+	    (m.cd & 0b1) << 4 |
+	    (m.rcode as u8 & 0b1111);
+	self.c.write_all(&flags)?;
+	let arcount = m.additional.len() + m.edns.is_some() as usize;
+	self.c.write_u16::<BigEndian>(m.questions.len() as u16)?;
+	self.c.write_u16::<BigEndian>(m.answers.len() as u16)?; // an
+	self.c.write_u16::<BigEndian>(m.nameservers.len() as u16)?; // ns
+	self.c.write_u16::<BigEndian>(arcount as u16)?; // ad
+	for q in &m.questions {
             self.nw.write(&mut self.c, &q.name)?;
-            self.c.write_u16::<BigEndian>(u16::from(q.qtype)).expect("oops");
-            self.c.write_u16::<BigEndian>(u16::from(q.class)).expect("oops");
+	    self.c.write_u16::<BigEndian>(u16::from(q.qtype))?;
+	    self.c.write_u16::<BigEndian>(u16::from(q.class))?;
         }
-        for a in &self.m.answers {
-            self.nw.write(&mut self.c, &a.name)?;
-            self.c.write_u16::<BigEndian>(u16::from(a.rtype)).expect("oops");
-            self.c.write_u16::<BigEndian>(u16::from(a.class)).expect("oops");
-            self.c.write_u32::<BigEndian>(a.ttl).expect("oops");
-	    match &a.data {
-		dns::ResourceData::IPv4(addr) => self.write_a(&addr)?,
-		dns::ResourceData::Ns(name) => self.write_ns(&name)?,
-		dns::ResourceData::CName(name) => self.write_cname(&name)?,
-		dns::ResourceData::Soa(soa) => self.write_soa(&soa)?,
-		dns::ResourceData::Ptr(name) => self.write_ptr(&name)?,
-		dns::ResourceData::Mx(mx) => self.write_mx(&mx)?,
-		dns::ResourceData::Txt(txt) => self.write_txt(&txt)?,
-		dns::ResourceData::IPv6(addr) => self.write_aaaa(&addr)?,
-		dns::ResourceData::Https(https) => self.write_https(&https)?,
-		_ => {println!("IGNORING ANSWER!"); ()},
-	    }
+	for a in m.answers.iter().chain(&m.nameservers).chain(&m.additional) {
+	    self.write_resource(a)?;
         }
-	for a in &self.m.nameservers {
-            self.nw.write(&mut self.c, &a.name)?;
-            self.c.write_u16::<BigEndian>(u16::from(a.rtype)).expect("oops");
-            self.c.write_u16::<BigEndian>(u16::from(a.class)).expect("oops");
-            self.c.write_u32::<BigEndian>(a.ttl).expect("oops");
-	    match &a.data {
-		dns::ResourceData::IPv4(addr) => self.write_a(&addr)?,
-		dns::ResourceData::Ns(name) => self.write_ns(&name)?,
-		dns::ResourceData::CName(name) => self.write_cname(&name)?,
-		dns::ResourceData::Soa(soa) => self.write_soa(&soa)?,
-		dns::ResourceData::Ptr(name) => self.write_ptr(&name)?,
-		dns::ResourceData::Mx(mx) => self.write_mx(&mx)?,
-		dns::ResourceData::Txt(txt) => self.write_txt(&txt)?,
-		dns::ResourceData::IPv6(addr) => self.write_aaaa(&addr)?,
-		dns::ResourceData::Https(https) => self.write_https(&https)?,
-		_ => {println!("IGNORING NAMESERVER!"); ()},
-	    }
+	if let Some(edns) = &m.edns {
+	    self.write_opt(edns)?;
         }
         Ok(())
     }
@@ -470,16 +863,69 @@ impl<'a> MessageWriter<'_> {
 
 impl Message {
     
-    pub fn from(data: &mut [u8]) -> Result<Message, std::io::Error> {
-        MessageParser::new(data).parse()
+    pub fn from(data: &[u8]) -> Result<Message, MessageError> {
+	let view = view::MessageView::new(data)?;
+	let mut m = view.header();
+	for q in view.questions() {
+	    let q = q?;
+	    m.questions.push(Question{
+		name: q.name.to_string(),
+		qtype: q.qtype,
+		class: q.class,
+	    });
+	}
+	for r in view.records() {
+	    let r = r?;
+	    if let dns::RecordType::OPT = r.rtype {
+		if r.section != view::Section::Additional {
+		    return Err(MessageError::Malformed("OPT outside additional section".to_owned()));
+		}
+		if m.edns.is_some() {
+		    return Err(MessageError::Malformed("Multiple OPT records".to_owned()));
+		}
+		let mut p = MessageParser::at(data, r.offset);
+		m.edns = Some(p.parse_opt(u16::from(r.class), r.ttl, r.rdata.len() as u64)?);
+		continue;
+	    }
+	    let rr = dns::ResourceRecord{
+		name: r.name.to_string(),
+		rtype: r.rtype,
+		class: r.class,
+		ttl: r.ttl,
+		data: r.data()?,
+	    };
+	    match r.section {
+		view::Section::Answer => m.answers.push(rr),
+		view::Section::Authority => m.nameservers.push(rr),
+		view::Section::Additional => m.additional.push(rr),
+	    }
+	}
+	Ok(m)
     }
 
-    pub fn into_bytes(&mut self) -> Result<Vec::<u8>, std::io::Error> {
+    pub fn into_bytes(&mut self) -> Result<Vec::<u8>, MessageError> {
         let mut buffer = Vec::<u8>::new();
         MessageWriter::new(&self, &mut buffer).into_bytes()?;
         Ok(buffer)
     }
 
+    // Drops all records and sets the TC bit, for answers that do not fit
+    // the transport.
+    pub fn truncate(&mut self) {
+	self.tc = 1;
+	self.answers.clear();
+	self.nameservers.clear();
+	self.additional.clear();
+    }
+
+    // The 12 bit rcode, combining the header and the EDNS extended rcode.
+    pub fn full_rcode(&self) -> u32 {
+	match &self.edns {
+	    Some(edns) => (edns.extended_rcode as u32) << 4 | self.rcode,
+	    None => self.rcode,
+	}
+    }
+
     pub fn new() -> Message {
         return Message{
             id: 0,
@@ -496,6 +942,269 @@ impl Message {
             answers: Vec::new(),
             nameservers: Vec::new(),
             additional: Vec::new(),
+	    edns: None,
         };
     }
 }
+
+impl Default for Message {
+    fn default() -> Message {
+	Message::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str, qtype: dns::RecordType) -> Message {
+	let mut m = Message::new();
+	m.id = 0x1234;
+	m.rd = 1;
+	m.questions.push(Question{
+	    name: name.to_owned(),
+	    qtype,
+	    class: dns::RecordClass::IN,
+	});
+	m
+    }
+
+    fn a_record(name: &str, addr: [u8; 4]) -> dns::ResourceRecord {
+	dns::ResourceRecord{
+	    name: name.to_owned(),
+	    rtype: dns::RecordType::A,
+	    class: dns::RecordClass::IN,
+	    ttl: 300,
+	    data: dns::ResourceData::IPv4(Ipv4Addr::from(addr)),
+	}
+    }
+
+    fn roundtrip(m: &mut Message) -> Message {
+	let data = m.into_bytes().unwrap();
+	Message::from(&data).unwrap()
+    }
+
+    #[test]
+    fn test_edns_roundtrip() {
+	let mut m = query("example.com.", dns::RecordType::A);
+	let mut edns = Edns::new(1232);
+	edns.dnssec_ok = true;
+	edns.options.push(EdnsOption{code: 10, data: vec![1, 2, 3, 4, 5, 6, 7, 8]});
+	m.edns = Some(edns);
+	let p = roundtrip(&mut m);
+	let edns = p.edns.unwrap();
+	assert_eq!(edns.udp_payload_size, 1232);
+	assert_eq!(edns.version, 0);
+	assert!(edns.dnssec_ok);
+	assert_eq!(edns.options.len(), 1);
+	assert_eq!(edns.options[0].code, 10);
+	assert_eq!(edns.options[0].data, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+	assert!(p.additional.is_empty());
+    }
+
+    #[test]
+    fn test_additional_and_extended_rcode() {
+	let mut m = query("example.com.", dns::RecordType::A);
+	m.qr = 1;
+	m.rcode = 16; // BADVERS
+	m.edns = Some(Edns::new(512));
+	m.answers.push(a_record("example.com.", [1, 2, 3, 4]));
+	m.additional.push(a_record("ns.example.com.", [5, 6, 7, 8]));
+	let p = roundtrip(&mut m);
+	assert_eq!(p.rcode, 0);
+	assert_eq!(p.full_rcode(), 16);
+	assert_eq!(p.answers.len(), 1);
+	assert_eq!(p.additional.len(), 1);
+	assert_eq!(p.additional[0].name, "ns.example.com.");
+    }
+
+    #[test]
+    fn test_malformed() {
+	let mut data = query("example.com.", dns::RecordType::A).into_bytes().unwrap();
+	let header = view::MessageView::new(&data[..12]).unwrap().header();
+	assert_eq!(header.id, 0x1234);
+	assert!(matches!(Message::from(&data[..20]), Err(MessageError::Truncated)));
+	assert!(matches!(view::MessageView::new(&data[..5]), Err(MessageError::Truncated)));
+	// Name made of a pointer to itself.
+	data[12] = 0xc0;
+	data[13] = 12;
+	assert!(matches!(Message::from(&data), Err(MessageError::Malformed(_))));
+	// Pointer past the end.
+	data[13] = 0xff;
+	assert!(matches!(Message::from(&data), Err(MessageError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_bad_rdlen() {
+	let mut m = query("example.com.", dns::RecordType::A);
+	m.answers.push(a_record("example.com.", [1, 2, 3, 4]));
+	let mut data = m.into_bytes().unwrap();
+	let rdlen = data.len() - 6;
+	data[rdlen + 1] = 2; // A with a 2 byte RDATA
+	assert!(matches!(Message::from(&data), Err(MessageError::Malformed(_))));
+	data[rdlen + 1] = 200;
+	assert!(matches!(Message::from(&data), Err(MessageError::Truncated)));
+    }
+
+    #[test]
+    fn test_typed_rdata_roundtrip() {
+	let mut m = query("example.com.", dns::RecordType::UNKNOWN(255));
+	let data = vec![
+	    dns::ResourceData::Srv(dns::Srv{priority: 10, weight: 5, port: 443, target: "example.com.".to_owned()}),
+	    dns::ResourceData::Naptr(dns::Naptr{
+		order: 100, preference: 10, flags: b"S".to_vec(), services: b"SIP+D2U".to_vec(),
+		regexp: Vec::new(), replacement: "_sip._udp.example.com.".to_owned(),
+	    }),
+	    dns::ResourceData::Ds(dns::Ds{key_tag: 1, algorithm: 13, digest_type: 2, digest: vec![0xab; 32]}),
+	    dns::ResourceData::Sshfp(dns::Sshfp{algorithm: 4, fp_type: 2, fingerprint: vec![1; 32]}),
+	    dns::ResourceData::Rrsig(dns::Rrsig{
+		type_covered: dns::RecordType::SRV, algorithm: 13, labels: 2, original_ttl: 300,
+		expiration: 2, inception: 1, key_tag: 7, signer_name: "example.com.".to_owned(),
+		signature: vec![9; 64],
+	    }),
+	    dns::ResourceData::Nsec(dns::Nsec{
+		next_domain: "a.example.com.".to_owned(),
+		types: vec![dns::RecordType::A, dns::RecordType::NSEC, dns::RecordType::CAA],
+	    }),
+	    dns::ResourceData::Dnskey(dns::Dnskey{flags: 257, protocol: 3, algorithm: 13, public_key: vec![2; 64]}),
+	    dns::ResourceData::Nsec3(dns::Nsec3{
+		hash_algorithm: 1, flags: 0, iterations: 0, salt: vec![0xaa, 0xbb],
+		next_hashed: vec![3; 20], types: vec![dns::RecordType::AAAA],
+	    }),
+	    dns::ResourceData::Tlsa(dns::Tlsa{usage: 3, selector: 1, matching_type: 1, data: vec![4; 32]}),
+	    dns::ResourceData::Svcb(dns::Svcb{domain_name: "svc.example.com.".to_owned(), form: dns::SvcbForm::ALIASFORM}),
+	    dns::ResourceData::Caa(dns::Caa{flags: 128, tag: "issue".to_owned(), value: b"ca.example".to_vec()}),
+	];
+	for d in &data {
+	    let rtype = match d {
+		dns::ResourceData::Srv(_) => dns::RecordType::SRV,
+		dns::ResourceData::Naptr(_) => dns::RecordType::NAPTR,
+		dns::ResourceData::Ds(_) => dns::RecordType::DS,
+		dns::ResourceData::Sshfp(_) => dns::RecordType::SSHFP,
+		dns::ResourceData::Rrsig(_) => dns::RecordType::RRSIG,
+		dns::ResourceData::Nsec(_) => dns::RecordType::NSEC,
+		dns::ResourceData::Dnskey(_) => dns::RecordType::DNSKEY,
+		dns::ResourceData::Nsec3(_) => dns::RecordType::NSEC3,
+		dns::ResourceData::Tlsa(_) => dns::RecordType::TLSA,
+		dns::ResourceData::Svcb(_) => dns::RecordType::SVCB,
+		_ => dns::RecordType::CAA,
+	    };
+	    m.answers.push(dns::ResourceRecord{
+		name: "example.com.".to_owned(),
+		rtype,
+		class: dns::RecordClass::IN,
+		ttl: 300,
+		data: d.clone(),
+	    });
+	}
+	let bytes = m.into_bytes().unwrap();
+	// The SRV target follows the owner name and would be a pointer if
+	// it were compressed.
+	let srv = 12 + 13 + 4 + 2 + 10 + 6;
+	assert_eq!(&bytes[srv..srv + 13], b"\x07example\x03com\x00");
+	let p = roundtrip(&mut m);
+	assert_eq!(p.answers.len(), data.len());
+	for (a, d) in p.answers.iter().zip(&data) {
+	    assert_eq!(a.data.to_string(), d.to_string());
+	}
+	assert!(matches!(p.answers[9].data, dns::ResourceData::Svcb(_)));
+    }
+
+    #[test]
+    fn test_unknown_rdata() {
+	let mut m = query("example.com.", dns::RecordType::UNKNOWN(65280));
+	m.answers.push(a_record("example.com.", [1, 2, 3, 4]));
+	let mut data = m.into_bytes().unwrap();
+	// Turn the A record into a private use type, its RDATA has to come
+	// back byte for byte.
+	let rtype = data.len() - 14;
+	data[rtype..rtype + 2].copy_from_slice(&65280u16.to_be_bytes());
+	let mut p = Message::from(&data).unwrap();
+	assert!(matches!(p.answers[0].rtype, dns::RecordType::UNKNOWN(65280)));
+	assert_eq!(p.answers[0].data.to_string(), "\\# 4 01020304");
+	assert_eq!(p.into_bytes().unwrap(), data);
+    }
+
+    // MINFO and RP names pointing back into the upstream message are
+    // decompressed and written out in full.
+    #[test]
+    fn test_names_rdata() {
+	let mut data = b"\x12\x34\x81\x80\x00\x01\x00\x02\x00\x00\x00\x00\
+			 \x07example\x03com\x00\x00\x0e\x00\x01".to_vec();
+	data.extend_from_slice(b"\xc0\x0c\x00\x0e\x00\x01\x00\x00\x01\x2c\x00\x0a\
+				 \x05admin\xc0\x0c\xc0\x0c");
+	data.extend_from_slice(b"\xc0\x0c\x00\x11\x00\x01\x00\x00\x01\x2c\x00\x0d\
+				 \x04root\xc0\x0c\x03txt\xc0\x0c");
+	let mut m = Message::from(&data).unwrap();
+	assert!(matches!(m.answers[0].rtype, dns::RecordType::MINFO));
+	assert_eq!(m.answers[0].data.to_string(), "admin.example.com. example.com.");
+	assert!(matches!(m.answers[1].rtype, dns::RecordType::RP));
+	assert_eq!(m.answers[1].data.to_string(), "root.example.com. txt.example.com.");
+	let bytes = m.into_bytes().unwrap();
+	assert!(bytes.windows(19).any(|w| w == b"\x05admin\x07example\x03com\x00"));
+	let p = Message::from(&bytes).unwrap();
+	assert_eq!(p.answers[1].data.to_string(), "root.example.com. txt.example.com.");
+	let mut m = query("example.com.", dns::RecordType::RT);
+	m.answers.push(dns::ResourceRecord{
+	    name: "example.com.".to_owned(),
+	    rtype: dns::RecordType::RT,
+	    class: dns::RecordClass::IN,
+	    ttl: 300,
+	    data: dns::ResourceData::Names(dns::Names{number: Some(10), names: vec!["relay.example.com.".to_owned()]}),
+	});
+	assert_eq!(roundtrip(&mut m).answers[0].data.to_string(), "10 relay.example.com.");
+    }
+
+    #[test]
+    fn test_txt() {
+	let mut m = query("example.com.", dns::RecordType::TXT);
+	let strings = vec![vec![b'a'; 255], b"\0\xff binary".to_vec(), Vec::new()];
+	m.answers.push(dns::ResourceRecord{
+	    name: "example.com.".to_owned(),
+	    rtype: dns::RecordType::TXT,
+	    class: dns::RecordClass::IN,
+	    ttl: 300,
+	    data: dns::ResourceData::Txt(strings.clone()),
+	});
+	let mut data = m.into_bytes().unwrap();
+	let mut p = Message::from(&data).unwrap();
+	match &p.answers[0].data {
+	    dns::ResourceData::Txt(t) => assert_eq!(*t, strings),
+	    d => panic!("{:?}", d),
+	}
+	assert_eq!(p.answers[0].data.txt_joined().unwrap().len(), 255 + 9);
+	assert_eq!(p.into_bytes().unwrap(), data);
+	// The second string runs past the RDATA.
+	let rdlen = data.len() - 256 - 10 - 1 - 2;
+	data[rdlen..rdlen + 2].copy_from_slice(&(256u16 + 5).to_be_bytes());
+	assert!(matches!(Message::from(&data), Err(MessageError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_classes() {
+	for class in [dns::RecordClass::CH, dns::RecordClass::HS, dns::RecordClass::NONE,
+		      dns::RecordClass::ANY, dns::RecordClass::UNKNOWN(1234)] {
+	    let mut m = query("version.bind.", dns::RecordType::TXT);
+	    m.questions[0].class = class;
+	    m.answers.push(dns::ResourceRecord{
+		name: "version.bind.".to_owned(),
+		rtype: dns::RecordType::TXT,
+		class,
+		ttl: 0,
+		data: dns::ResourceData::Txt(vec![b"1.0".to_vec()]),
+	    });
+	    let p = roundtrip(&mut m);
+	    assert_eq!(u16::from(p.questions[0].class), u16::from(class));
+	    assert_eq!(u16::from(p.answers[0].class), u16::from(class));
+	}
+    }
+
+    #[test]
+    fn test_no_edns() {
+	let mut m = query("example.com.", dns::RecordType::AAAA);
+	let p = roundtrip(&mut m);
+	assert!(p.edns.is_none());
+	assert_eq!(p.id, 0x1234);
+	assert_eq!(p.rd, 1);
+    }
+}
@@ -1,7 +1,6 @@
 use byteorder::*;
 use std::io::Cursor;
 use std::io::Write;
-use std::io::Read;
 
 struct LabelNode {
     label: String,
@@ -190,121 +189,20 @@ impl NameWriter {
         }
         Ok(())
     }
-}
-
-#[derive(Debug,Clone)]
-struct ReadNode {
-    label: String,
-    next: Option<usize>,
-}
-
-struct ReadTree {
-    labels: Vec<Option<ReadNode>>,
-}
-
-impl ReadTree {
-    pub fn new() -> ReadTree {
-        ReadTree{
-            labels: Vec::new(),
-        }
-    }
-    pub fn insert(&mut self, labels: &[LabelPos], next: Option<usize>) {
-        for i in 0..labels.len() {
-            let p = &labels[i];
-            if p.pos >= self.labels.len() {
-                self.labels.resize(p.pos + 1, None);
-            }
-            let np = if i + 1 < labels.len() {
-                Some(labels[i + 1].pos)
-            } else {
-                next
-            };
-            self.labels[p.pos] = Some(ReadNode{
-                label: p.label.to_owned(),
-                next: np,
-            });
-        }
-    }
-    pub fn load(&self, pos: usize) -> String {
-        let mut labels = Vec::<&str>::new();
-        let mut p = pos;
-        loop {
-            if let Some(n) = &self.labels[p] {
-                labels.push(&n.label);
-                if let Some(np) = n.next {
-                    p = np;
-                } else {
-                    break;
-                }
-            } else {
-                break;
-            }
-        }
-        return labels.join(".") + ".";
-    }
-}
-
-pub struct NameReader {
-    tree: ReadTree
-}
 
-enum LabelOrPointer {
-    Label(usize, String),
-    Pointer(usize),
-    End,
-}
-
-impl NameReader {
-    pub fn new() -> NameReader {
-        NameReader{
-            tree: ReadTree::new(),
-        }
-    }
-
-    fn read_label<T>(c: &mut Cursor<T>) -> Result<LabelOrPointer, std::io::Error>
-    where std::io::Cursor<T>: std::io::BufRead {
-        let pos = c.position() as usize;
-        let len = c.read_u8()?;
-        if len == 0x0 {
-            return Ok(LabelOrPointer::End);
-        } else if len & 0xc0 == 0xc0 {
-            // it's pointer
-            let len2 = c.read_u8()?;
-            return Ok(LabelOrPointer::Pointer((len as usize & !0xc0) << 8 | len2 as usize));
-        } else {
-            let mut data = Vec::<u8>::new();
-            c.take(len as u64).read_to_end(&mut data)?;
-            return Ok(LabelOrPointer::Label(pos, String::from_utf8_lossy(&data).to_string()));
-        }
-    }
-
-    pub fn read<T>(&mut self, c: &mut Cursor<T>) -> Result<String, std::io::Error>
-    where std::io::Cursor<T>: std::io::BufRead {
-        let mut labels = Vec::<LabelPos>::new();
-        let mut next: Option<usize> = None;
-        let start = c.position() as usize;
-        loop {
-            match Self::read_label(c)? {
-                LabelOrPointer::Label(pos, label) => {
-                    labels.push(LabelPos::new(&label, pos));
-                },
-                LabelOrPointer::Pointer(pos) => {
-                    next = Some(pos);
-                    break;
-                },
-                LabelOrPointer::End => {
-                    break;
-                },
-            }
-        }
-        
-        if labels.len() != 0 {
-            self.tree.insert(&labels, next);
-            return Ok(self.tree.load(start));
-        } else if let Some(p) = next {
-            return Ok(self.tree.load(p));
-        } else {
-            return Ok(".".to_owned());
-        }
+    // Writes name in full, for RDATA of types that forbid compression
+    // (RFC 3597 section 4). Returns the size written.
+    pub fn write_uncompressed<T>(&mut self, c: &mut Cursor<T>, name: &str) -> Result<usize, std::io::Error>
+    where std::io::Cursor<T>: std::io::Write {
+	let mut size = 1;
+	for l in name.split('.').filter(|p| !p.is_empty()) {
+	    let length: u8 = l.len().try_into()
+		.map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "label too long"))?;
+	    c.write_u8(length)?;
+	    c.write_all(l.as_bytes())?;
+	    size += 1 + l.len();
+	}
+	c.write_u8(0)?;
+	Ok(size)
     }
 }
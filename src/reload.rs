@@ -0,0 +1,142 @@
+use log::{error, info, warn};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+use crate::config::{Args, Config};
+use crate::hosts::Hosts;
+use crate::tls::CertStore;
+use crate::upstream::UpstreamPool;
+use crate::Resolver;
+
+// How often the hosts file modification time is checked.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// Loads the hosts file and publishes it. Bad lines are skipped with a
+// warning, as at startup. If the file can't be read the previous data
+// stays in place.
+fn reload_hosts(path: &Path, hosts: &watch::Sender<Arc<Hosts>>) {
+    match Hosts::load(path) {
+	Ok((h, errors)) => {
+	    for e in errors {
+		warn!("{}", e);
+	    }
+	    info!("Reloaded {}", path.display());
+	    hosts.send_replace(Arc::new(h));
+	},
+	Err(e) => error!("{}: {}, keeping previous hosts", path.display(), e),
+    }
+}
+
+// Re-reads the configuration file and applies what can change at runtime.
+// Listener addresses are only read at startup.
+fn reload_config(args: &Args, config: &mut Config, resolver: &Resolver,
+		 upstreams: &watch::Sender<Arc<UpstreamPool>>) {
+    let new = match Config::from_args(args.clone()) {
+	Ok(new) => new,
+	Err(e) => {
+	    error!("Reload failed, keeping previous configuration: {}", e);
+	    return;
+	},
+    };
+    // The pool is only rebuilt when [upstream] changed, so it keeps its
+    // RTT and case statistics otherwise.
+    let pool = if new.upstream != config.upstream {
+	match new.upstream.pool() {
+	    Ok(pool) => Some(pool),
+	    Err(e) => {
+		error!("Reload failed, keeping previous configuration: {}", e);
+		return;
+	    },
+	}
+    } else {
+	None
+    };
+    if new.listen != config.listen {
+	warn!("Listener changes need a restart");
+    }
+    if new.limits != config.limits {
+	warn!("Limit changes need a restart");
+    }
+    if new.tls != config.tls {
+	warn!("TLS file path changes need a restart");
+    }
+    log::set_max_level(new.log_level());
+    if let Some(pool) = pool {
+	info!("Upstreams changed");
+	upstreams.send_replace(Arc::new(pool));
+    }
+    resolver.cache.lock().unwrap().set_capacity(new.cache.size);
+    *resolver.chaos.lock().unwrap() = new.chaos.clone();
+    info!("Reloaded configuration");
+    *config = new;
+}
+
+fn reload_certs(certs: &CertStore) {
+    match certs.reload() {
+	Ok(()) => info!("Reloaded TLS certificate"),
+	Err(e) => error!("{}, keeping previous TLS certificate", e),
+    }
+}
+
+// Reloads the hosts file and TLS certificate when they change on disk, and
+// everything on SIGHUP. SIGUSR1 logs upstream statistics.
+pub async fn run(args: Args, mut config: Config, resolver: Arc<Resolver>,
+		 hosts: watch::Sender<Arc<Hosts>>,
+		 upstreams: watch::Sender<Arc<UpstreamPool>>,
+		 certs: Option<Arc<CertStore>>) -> Result<(), std::io::Error> {
+    let mut hup = signal(SignalKind::hangup())?;
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+    let mut hosts_path: Option<PathBuf> = config.hosts.clone();
+    let mut last_mtime = hosts_path.as_deref().and_then(mtime);
+    let cert_mtimes = |c: &CertStore| c.paths().map(mtime);
+    let mut last_cert_mtimes = certs.as_deref().map(cert_mtimes);
+    loop {
+	tokio::select! {
+	    _ = hup.recv() => {
+		info!("SIGHUP, reloading");
+		reload_config(&args, &mut config, &resolver, &upstreams);
+		if config.hosts != hosts_path {
+		    hosts_path = config.hosts.clone();
+		    if hosts_path.is_none() {
+			hosts.send_replace(Arc::new(Hosts::new()));
+		    }
+		}
+		if let Some(path) = &hosts_path {
+		    last_mtime = mtime(path);
+		    reload_hosts(path, &hosts);
+		}
+		if let Some(certs) = &certs {
+		    last_cert_mtimes = Some(cert_mtimes(certs));
+		    reload_certs(certs);
+		}
+	    },
+	    _ = usr1.recv() => {
+		upstreams.borrow().log_stats();
+	    },
+	    _ = poll.tick() => {
+		if let Some(path) = &hosts_path {
+		    let m = mtime(path);
+		    if m != last_mtime {
+			last_mtime = m;
+			reload_hosts(path, &hosts);
+		    }
+		}
+		if let Some(certs) = &certs {
+		    let m = Some(cert_mtimes(certs));
+		    if m != last_cert_mtimes {
+			last_cert_mtimes = m;
+			reload_certs(certs);
+		    }
+		}
+	    },
+	}
+    }
+}
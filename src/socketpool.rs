@@ -0,0 +1,218 @@
+use log::debug;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+use crate::message::Message;
+use crate::tokengen::TokenGen;
+use crate::{genid, EDNS_PAYLOAD_SIZE};
+
+// Sockets for plain DNS upstream queries, shared by all questions. Each
+// query goes out on a random socket with a random ID, and the answers
+// are matched back by ID and source address. Sockets are replaced after
+// a number of queries so the source ports keep changing.
+
+// Sockets per address family.
+const SOCKETS: usize = 8;
+// Queries sent on a socket before it is replaced by one on a new port.
+const MAX_USES: usize = 512;
+// How often a replaced socket checks whether it can be closed.
+const RETIRE_CHECK: Duration = Duration::from_secs(1);
+// Random IDs tried before giving up on a busy socket.
+const ID_ATTEMPTS: usize = 16;
+
+struct Pending {
+    ids: TokenGen,
+    // Queries waiting for answers, by ID, with the upstream they went to.
+    waiters: HashMap<u16, (SocketAddr, mpsc::Sender<Vec<u8>>)>,
+    // Set once the receiver has stopped, no new queries may be added.
+    closed: bool,
+}
+
+struct PoolSocket {
+    socket: UdpSocket,
+    pending: Mutex<Pending>,
+    uses: AtomicUsize,
+    retired: AtomicBool,
+}
+
+impl PoolSocket {
+    // Picks a free random ID for a query to addr.
+    fn register(&self, addr: SocketAddr, tx: mpsc::Sender<Vec<u8>>) -> Option<u16> {
+	let mut pending = self.pending.lock().unwrap();
+	if pending.closed {
+	    return None;
+	}
+	for _ in 0..ID_ATTEMPTS {
+	    let id = genid();
+	    if pending.ids.try_acquire(id as u32) {
+		pending.waiters.insert(id, (addr, tx));
+		return Some(id);
+	    }
+	}
+	None
+    }
+
+    fn unregister(&self, id: u16) {
+	let mut pending = self.pending.lock().unwrap();
+	pending.waiters.remove(&id);
+	pending.ids.release(id as u32);
+    }
+
+    fn dispatch(&self, data: &[u8], src: SocketAddr) {
+	let id = match data {
+	    [a, b, ..] => u16::from_be_bytes([*a, *b]),
+	    _ => return,
+	};
+	let pending = self.pending.lock().unwrap();
+	match pending.waiters.get(&id) {
+	    Some((addr, tx)) if *addr == src => {
+		// A full queue means a flood of bogus answers, drop them.
+		let _ = tx.try_send(data.to_vec());
+	    },
+	    _ => debug!("Discarding unexpected upstream answer from {} with ID {}", src, id),
+	}
+    }
+
+    // True when retired and nothing is waiting any more, the socket then
+    // stops accepting queries.
+    fn close_if_idle(&self) -> bool {
+	if !self.retired.load(Ordering::Relaxed) {
+	    return false;
+	}
+	let mut pending = self.pending.lock().unwrap();
+	pending.closed = pending.waiters.is_empty();
+	pending.closed
+    }
+}
+
+async fn receive(socket: Arc<PoolSocket>) {
+    let mut buf = [0; EDNS_PAYLOAD_SIZE as usize];
+    loop {
+	match tokio::time::timeout(RETIRE_CHECK, socket.socket.recv_from(&mut buf)).await {
+	    Ok(Ok((amt, src))) => socket.dispatch(&buf[..amt], src),
+	    Ok(Err(e)) => debug!("Upstream socket receive failed: {}", e),
+	    Err(_) => (),
+	}
+	if socket.close_if_idle() {
+	    break;
+	}
+    }
+}
+
+// A query sent through the pool, receives the datagrams from its upstream
+// with its ID. They still have to be checked against the query.
+pub struct Exchange {
+    socket: Arc<PoolSocket>,
+    id: u16,
+    answers: mpsc::Receiver<Vec<u8>>,
+}
+
+impl Exchange {
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+	self.answers.recv().await
+    }
+}
+
+impl Drop for Exchange {
+    fn drop(&mut self) {
+	self.socket.unregister(self.id);
+    }
+}
+
+pub struct SocketPool {
+    v4: Mutex<Vec<Option<Arc<PoolSocket>>>>,
+    v6: Mutex<Vec<Option<Arc<PoolSocket>>>>,
+}
+
+impl SocketPool {
+    pub fn new() -> SocketPool {
+	SocketPool{
+	    v4: Mutex::new(vec![None; SOCKETS]),
+	    v6: Mutex::new(vec![None; SOCKETS]),
+	}
+    }
+
+    // Returns a random socket of the family, binding a new one if the slot
+    // is empty or its socket is used up.
+    async fn socket(&self, ipv4: bool) -> Result<Arc<PoolSocket>, Error> {
+	let slots = if ipv4 { &self.v4 } else { &self.v6 };
+	let i = genid() as usize % SOCKETS;
+	if let Some(socket) = &slots.lock().unwrap()[i] {
+	    if socket.uses.fetch_add(1, Ordering::Relaxed) < MAX_USES {
+		return Ok(socket.clone());
+	    }
+	}
+	// The kernel picks a random ephemeral port.
+	let bind_addr = if ipv4 { "0.0.0.0:0" } else { "[::]:0" };
+	let socket = Arc::new(PoolSocket{
+	    socket: UdpSocket::bind(bind_addr).await?,
+	    pending: Mutex::new(Pending{ids: TokenGen::new(), waiters: HashMap::new(), closed: false}),
+	    uses: AtomicUsize::new(1),
+	    retired: AtomicBool::new(false),
+	});
+	debug!("New upstream socket on {:?}", socket.socket.local_addr());
+	tokio::spawn(receive(socket.clone()));
+	if let Some(old) = slots.lock().unwrap()[i].replace(socket.clone()) {
+	    old.retired.store(true, Ordering::Relaxed);
+	}
+	Ok(socket)
+    }
+
+    // Sends query to addr, setting its ID.
+    pub async fn send(&self, addr: SocketAddr, query: &mut Message) -> Result<Exchange, Error> {
+	let (tx, answers) = mpsc::channel(4);
+	let mut attempts = 0;
+	let (socket, id) = loop {
+	    let socket = self.socket(addr.is_ipv4()).await?;
+	    if let Some(id) = socket.register(addr, tx.clone()) {
+		break (socket, id);
+	    }
+	    attempts += 1;
+	    if attempts == SOCKETS {
+		return Err(Error::new(ErrorKind::WouldBlock, "No free upstream query ID"));
+	    }
+	};
+	let exchange = Exchange{socket, id, answers};
+	query.id = id as u32;
+	let data = query.into_bytes()?;
+	exchange.socket.socket.send_to(&data, addr).await?;
+	Ok(exchange)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dispatch() {
+	let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+	let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+	let addr = server.local_addr().unwrap();
+	let pool = SocketPool::new();
+	let mut a = pool.send(addr, &mut Message::new()).await.unwrap();
+	let mut b = pool.send(addr, &mut Message::new()).await.unwrap();
+	let mut buf = [0; 512];
+	let (_, src_a) = server.recv_from(&mut buf).await.unwrap();
+	let id_a = [buf[0], buf[1]];
+	let (_, src_b) = server.recv_from(&mut buf).await.unwrap();
+	let id_b = [buf[0], buf[1]];
+	assert_eq!(u16::from_be_bytes(id_a), a.id);
+	assert_eq!(u16::from_be_bytes(id_b), b.id);
+	// The right ID from the wrong address is ignored.
+	other.send_to(&id_a, src_a).await.unwrap();
+	server.send_to(&[id_b[0], id_b[1], 2], src_b).await.unwrap();
+	server.send_to(&[id_a[0], id_a[1], 1], src_a).await.unwrap();
+	assert_eq!(a.recv().await.unwrap(), vec![id_a[0], id_a[1], 1]);
+	assert_eq!(b.recv().await.unwrap(), vec![id_b[0], id_b[1], 2]);
+	let socket = a.socket.clone();
+	drop(a);
+	assert!(!socket.pending.lock().unwrap().waiters.contains_key(&u16::from_be_bytes(id_a)));
+    }
+}
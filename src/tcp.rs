@@ -0,0 +1,56 @@
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// RFC 7766 recommends closing idle connections after a few seconds.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Reads one message using the RFC 1035 two byte length framing. Returns
+// None when the peer closed the connection between messages.
+pub async fn read_message<R>(r: &mut R) -> Result<Option<Vec<u8>>, std::io::Error>
+where R: AsyncRead + Unpin {
+    let mut len = [0u8; 2];
+    match r.read(&mut len).await? {
+	0 => return Ok(None),
+	// EOF after a single length byte is an error, not a clean close.
+	1 => { r.read_exact(&mut len[1..]).await?; },
+	_ => (),
+    }
+    let len = u16::from_be_bytes(len) as usize;
+    let mut data = vec![0u8; len];
+    r.read_exact(&mut data).await?;
+    Ok(Some(data))
+}
+
+pub async fn write_message<W>(w: &mut W, data: &[u8]) -> Result<(), std::io::Error>
+where W: AsyncWrite + Unpin {
+    let len: u16 = data.len().try_into()
+	.map_err(|_| Error::new(ErrorKind::InvalidInput, "Message too large for TCP"))?;
+    let mut buf = Vec::with_capacity(data.len() + 2);
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(data);
+    w.write_all(&buf).await?;
+    w.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_framing() {
+	let (mut a, mut b) = tokio::io::duplex(1024);
+	write_message(&mut a, b"hello").await.unwrap();
+	write_message(&mut a, b"").await.unwrap();
+	drop(a);
+	assert_eq!(read_message(&mut b).await.unwrap(), Some(b"hello".to_vec()));
+	assert_eq!(read_message(&mut b).await.unwrap(), Some(Vec::new()));
+	assert_eq!(read_message(&mut b).await.unwrap(), None);
+
+	let (mut a, mut b) = tokio::io::duplex(1024);
+	a.write_all(&[0]).await.unwrap();
+	drop(a);
+	let e = read_message(&mut b).await.unwrap_err();
+	assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -11,6 +11,9 @@ impl TokenGen {
 	}
     }
 
+    // Only the tests take the next free token, sockets pick theirs at
+    // random with try_acquire.
+    #[cfg(test)]
     pub fn acquire(&mut self) -> u32 {
 	for (i, v) in self.bitmap.iter_mut().enumerate() {
 	    if *v != u64::MAX {
@@ -20,11 +23,25 @@ impl TokenGen {
 	    }
 	}
 	let nv: u64 = 0x1;
-	let i = (self.bitmap.len() as u32 * 8 * mem::size_of::<u64>() as u32); 
+	let i = self.bitmap.len() as u32 * 8 * mem::size_of::<u64>() as u32;
 	self.bitmap.push(nv);
 	return i as u32;
     }
 
+    // Takes a specific token, false if it is already taken.
+    pub fn try_acquire(&mut self, token: u32) -> bool {
+	let i = (token / (8 * mem::size_of::<u64>() as u32)) as usize;
+	let o = token % (8 * mem::size_of::<u64>() as u32);
+	if i >= self.bitmap.len() {
+	    self.bitmap.resize(i + 1, 0);
+	}
+	if self.bitmap[i] & (1 << o) != 0 {
+	    return false;
+	}
+	self.bitmap[i] |= 1 << o;
+	true
+    }
+
     pub fn release(&mut self, token: u32) {
 	let i = (token / (8 * mem::size_of::<u64>() as u32)) as usize;
 	let o = token % (8 * mem::size_of::<u64>() as u32);
@@ -39,6 +56,7 @@ impl TokenGen {
 	}
     }
 
+    #[cfg(test)]
     pub fn len(&self) -> usize {
 	self.bitmap.len()
     }
@@ -64,4 +82,16 @@ mod tests {
 	    assert!(tg.len() == j as usize);
 	}
     }
+
+    #[test]
+    fn test_try_acquire() {
+	let mut tg = TokenGen::new();
+	assert!(tg.try_acquire(1000));
+	assert!(!tg.try_acquire(1000));
+	assert!(tg.len() == 16);
+	assert!(tg.acquire() == 0);
+	tg.release(1000);
+	assert!(tg.len() == 1);
+	assert!(tg.try_acquire(1000));
+    }
 }
@@ -0,0 +1,165 @@
+use hyper::client::connect::dns::Name;
+use hyper::client::HttpConnector;
+use hyper::header::{ACCEPT, CONTENT_TYPE};
+use hyper::service::Service;
+use hyper::{Body, Method, Request, Uri};
+use log::debug;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls;
+use tokio_rustls::TlsConnector;
+
+use crate::tcp;
+use crate::tls;
+
+// Encrypted upstream transports, DNS over TLS (RFC 7858) and DNS over
+// HTTPS (RFC 8484).
+
+// Idle connections kept open per DoT upstream.
+const MAX_IDLE: usize = 4;
+// Idle connections older than this are assumed closed by the server.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const DNS_MESSAGE: &str = "application/dns-message";
+
+pub struct TlsClient {
+    addr: SocketAddr,
+    server_name: rustls::ServerName,
+    connector: TlsConnector,
+    idle: Mutex<Vec<(Instant, TlsStream<TcpStream>)>>,
+}
+
+impl std::fmt::Debug for TlsClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	write!(f, "TlsClient({})", self.addr)
+    }
+}
+
+impl TlsClient {
+    pub fn new(addr: SocketAddr, server_name: &str, pins: &[tls::Pin]) -> Result<TlsClient, Error> {
+	let server_name = rustls::ServerName::try_from(server_name)
+	    .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("Invalid TLS server name {:?}", server_name)))?;
+	Ok(TlsClient{
+	    addr,
+	    server_name,
+	    connector: TlsConnector::from(tls::client_config(pins, &crate::dot::ALPN)),
+	    idle: Mutex::new(Vec::new()),
+	})
+    }
+
+    async fn connect(&self) -> Result<TlsStream<TcpStream>, Error> {
+	let stream = TcpStream::connect(self.addr).await?;
+	stream.set_nodelay(true)?;
+	self.connector.connect(self.server_name.clone(), stream).await
+    }
+
+    async fn roundtrip(stream: &mut TlsStream<TcpStream>, query: &[u8]) -> Result<Vec<u8>, Error> {
+	tcp::write_message(stream, query).await?;
+	tcp::read_message(stream).await?
+	    .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "Upstream closed TLS connection"))
+    }
+
+    fn take_idle(&self) -> Option<TlsStream<TcpStream>> {
+	let mut idle = self.idle.lock().unwrap();
+	idle.retain(|(since, _)| since.elapsed() < IDLE_TIMEOUT);
+	idle.pop().map(|(_, stream)| stream)
+    }
+
+    fn put_idle(&self, stream: TlsStream<TcpStream>) {
+	let mut idle = self.idle.lock().unwrap();
+	if idle.len() < MAX_IDLE {
+	    idle.push((Instant::now(), stream));
+	}
+    }
+
+    // Sends a query over an idle connection if there is one, else over a
+    // new one, and keeps the connection for the next query.
+    pub async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, Error> {
+	if let Some(mut stream) = self.take_idle() {
+	    match Self::roundtrip(&mut stream, query).await {
+		Ok(answer) => {
+		    self.put_idle(stream);
+		    return Ok(answer);
+		},
+		// The server may have closed it in the meantime.
+		Err(e) => debug!("Reused connection to {} failed: {}", self.addr, e),
+	    }
+	}
+	let mut stream = self.connect().await?;
+	let answer = Self::roundtrip(&mut stream, query).await?;
+	self.put_idle(stream);
+	Ok(answer)
+    }
+}
+
+// Resolves every host name to the configured address. The system
+// resolver may well be this proxy.
+#[derive(Clone)]
+struct FixedAddr(IpAddr);
+
+impl Service<Name> for FixedAddr {
+    type Response = std::iter::Once<SocketAddr>;
+    type Error = Error;
+    type Future = std::future::Ready<Result<Self::Response, Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+	Poll::Ready(Ok(()))
+    }
+
+    // hyper sets the port from the URI.
+    fn call(&mut self, _name: Name) -> Self::Future {
+	std::future::ready(Ok(std::iter::once(SocketAddr::new(self.0, 0))))
+    }
+}
+
+type HttpsConnector = hyper_rustls::HttpsConnector<HttpConnector<FixedAddr>>;
+
+// hyper keeps connections alive and multiplexes queries over HTTP/2.
+pub struct HttpsClient {
+    uri: Uri,
+    client: hyper::Client<HttpsConnector, Body>,
+}
+
+impl std::fmt::Debug for HttpsClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	write!(f, "HttpsClient({})", self.uri)
+    }
+}
+
+impl HttpsClient {
+    // Connects to addr whatever the URI's host is.
+    pub fn new(uri: Uri, addr: IpAddr, pins: &[tls::Pin]) -> HttpsClient {
+	let mut http = HttpConnector::new_with_resolver(FixedAddr(addr));
+	http.enforce_http(false);
+	let connector = hyper_rustls::HttpsConnectorBuilder::new()
+	    .with_tls_config((*tls::client_config(pins, &[])).clone())
+	    .https_only()
+	    .enable_http1()
+	    .enable_http2()
+	    .wrap_connector(http);
+	HttpsClient{
+	    uri,
+	    client: hyper::Client::builder().pool_idle_timeout(IDLE_TIMEOUT * 3).build(connector),
+	}
+    }
+
+    pub async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, Error> {
+	let req = Request::builder()
+	    .method(Method::POST)
+	    .uri(self.uri.clone())
+	    .header(CONTENT_TYPE, DNS_MESSAGE)
+	    .header(ACCEPT, DNS_MESSAGE)
+	    .body(Body::from(query.to_vec()))
+	    .map_err(Error::other)?;
+	let rsp = self.client.request(req).await.map_err(Error::other)?;
+	if !rsp.status().is_success() {
+	    return Err(Error::other(format!("Upstream HTTP status {}", rsp.status())));
+	}
+	let body = hyper::body::to_bytes(rsp.into_body()).await.map_err(Error::other)?;
+	Ok(body.to_vec())
+    }
+}
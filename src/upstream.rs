@@ -0,0 +1,325 @@
+use log::info;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::tls;
+use crate::transport::{HttpsClient, TlsClient};
+
+const DEFAULT_PORT: u16 = 53;
+const DEFAULT_TLS_PORT: u16 = 853;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    RoundRobin,
+    Random,
+    LowestLatency,
+    // Queries the fastest few at once and takes the first answer.
+    Race,
+}
+
+impl FromStr for Strategy {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Strategy, Self::Err> {
+	match s {
+	    "round-robin" => Ok(Strategy::RoundRobin),
+	    "random" => Ok(Strategy::Random),
+	    "lowest-latency" => Ok(Strategy::LowestLatency),
+	    "race" => Ok(Strategy::Race),
+	    _ => Err(Error::new(ErrorKind::InvalidInput, format!("Unknown upstream strategy {:?}", s))),
+	}
+    }
+}
+
+// Accepts "9.9.9.9", "9.9.9.9:5353", "2620:fe::fe" and "[2620:fe::fe]:53".
+pub fn parse_addr(s: &str) -> Result<SocketAddr, std::io::Error> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+	return Ok(addr);
+    }
+    match s.parse::<IpAddr>() {
+	Ok(ip) => Ok(SocketAddr::new(ip, DEFAULT_PORT)),
+	Err(_) => Err(Error::new(ErrorKind::InvalidInput, format!("Invalid upstream address {:?}", s))),
+    }
+}
+
+// How queries reach an upstream.
+#[derive(Debug)]
+pub enum Transport {
+    // UDP, retried over TCP when truncated.
+    Udp(SocketAddr),
+    Tls(TlsClient),
+    Https(Box<HttpsClient>),
+}
+
+#[derive(Debug)]
+pub struct Upstream {
+    // As configured, for logging.
+    pub name: String,
+    pub transport: Transport,
+    // Send plain DNS queries with the name's case randomized (DNS 0x20).
+    pub randomize_case: bool,
+    // Set once the upstream answered with the name's case changed.
+    mangles_case: AtomicBool,
+    // Smoothed round trip time in microseconds, 0 until the first answer.
+    rtt: AtomicU64,
+    // Races this upstream answered first.
+    wins: AtomicU64,
+}
+
+impl Upstream {
+    pub fn new(name: &str, transport: Transport) -> Upstream {
+	Upstream{
+	    name: name.to_owned(),
+	    transport,
+	    randomize_case: true,
+	    mangles_case: AtomicBool::new(false),
+	    rtt: AtomicU64::new(0),
+	    wins: AtomicU64::new(0),
+	}
+    }
+
+    // Accepts a plain address (see parse_addr), "tls://ip[:port]" or
+    // "https://host[:port]/path". server_name overrides the TLS name for
+    // tls:// upstreams, which defaults to the address. An https:// host
+    // that isn't an IP address needs addr, host names are never looked up
+    // as the system resolver may be this proxy. With pins the server key
+    // is checked against them instead of the web PKI.
+    pub fn parse(url: &str, server_name: Option<&str>, pins: &[tls::Pin],
+		 addr: Option<IpAddr>) -> Result<Upstream, std::io::Error> {
+	let invalid = |msg: &str| Error::new(ErrorKind::InvalidInput, format!("{:?}: {}", url, msg));
+	let scheme = url.split_once("://").map(|(scheme, _)| scheme);
+	if !matches!(scheme, Some("tls") | Some("https")) {
+	    if server_name.is_some() || !pins.is_empty() || addr.is_some() {
+		return Err(invalid("server_name, spki_pins and addr need tls:// or https://"));
+	    }
+	    return Ok(Upstream::new(url, Transport::Udp(parse_addr(url)?)));
+	}
+	let uri = url.parse::<hyper::Uri>().map_err(|e| invalid(&e.to_string()))?;
+	let host = uri.host().ok_or_else(|| invalid("missing host"))?;
+	let host = host.trim_start_matches('[').trim_end_matches(']');
+	let ip = host.parse::<IpAddr>().ok();
+	let transport = if scheme == Some("tls") {
+	    if uri.path() != "/" && !uri.path().is_empty() {
+		return Err(invalid("tls:// takes no path"));
+	    }
+	    if addr.is_some() {
+		return Err(invalid("addr is only used with https://"));
+	    }
+	    let ip = ip.ok_or_else(|| invalid("tls:// needs an IP address, set server_name for the certificate"))?;
+	    let port = uri.port_u16().unwrap_or(DEFAULT_TLS_PORT);
+	    Transport::Tls(TlsClient::new(SocketAddr::new(ip, port), server_name.unwrap_or(host), pins)?)
+	} else {
+	    if server_name.is_some() {
+		return Err(invalid("server_name is only used with tls://"));
+	    }
+	    let ip = match (ip, addr) {
+		(Some(_), Some(_)) => return Err(invalid("addr is only needed for host names")),
+		(Some(ip), None) | (None, Some(ip)) => ip,
+		(None, None) => return Err(invalid("set addr to the server's IP address")),
+	    };
+	    Transport::Https(Box::new(HttpsClient::new(uri, ip, pins)))
+	};
+	Ok(Upstream::new(url, transport))
+    }
+
+    // Whether to use DNS 0x20, unless the upstream was seen not to
+    // preserve case.
+    pub fn use_0x20(&self) -> bool {
+	self.randomize_case && !self.mangles_case.load(Ordering::Relaxed)
+    }
+
+    // Stops randomizing the case of queries to this upstream. Returns
+    // whether it was still on.
+    pub fn set_mangles_case(&self) -> bool {
+	!self.mangles_case.swap(true, Ordering::Relaxed)
+    }
+
+    fn update_rtt(&self, sample: Duration) {
+	let sample = sample.as_micros().min(u64::MAX as u128) as u64;
+	let old = self.rtt.load(Ordering::Relaxed);
+	let new = if old == 0 { sample } else { (old * 7 + sample) / 8 };
+	self.rtt.store(new.max(1), Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+pub struct UpstreamPool {
+    upstreams: Vec<Upstream>,
+    strategy: Strategy,
+    timeout: Duration,
+    race_width: usize,
+    next: AtomicUsize,
+}
+
+impl UpstreamPool {
+    pub fn new(upstreams: Vec<Upstream>, strategy: Strategy, timeout: Duration) -> UpstreamPool {
+	assert!(!upstreams.is_empty());
+	UpstreamPool{
+	    upstreams,
+	    strategy,
+	    timeout,
+	    race_width: 2,
+	    next: AtomicUsize::new(0),
+	}
+    }
+
+    pub fn get(&self, i: usize) -> &Upstream {
+	&self.upstreams[i]
+    }
+
+    pub fn timeout(&self) -> Duration {
+	self.timeout
+    }
+
+    pub fn set_race_width(&mut self, n: usize) {
+	self.race_width = n.max(1);
+    }
+
+    // How many upstreams to query at once.
+    pub fn race_width(&self) -> usize {
+	match self.strategy {
+	    Strategy::Race => self.race_width,
+	    _ => 1,
+	}
+    }
+
+    // Returns the order in which upstreams should be tried for one query.
+    // The first entry is the one picked by the strategy, the rest are
+    // failover candidates.
+    pub fn order(&self) -> Vec<usize> {
+	let n = self.upstreams.len();
+	match self.strategy {
+	    Strategy::RoundRobin => {
+		let first = self.next.fetch_add(1, Ordering::Relaxed) % n;
+		(0..n).map(|i| (first + i) % n).collect()
+	    },
+	    Strategy::Random => {
+		let first = random_index(n);
+		(0..n).map(|i| (first + i) % n).collect()
+	    },
+	    Strategy::LowestLatency | Strategy::Race => {
+		// Unmeasured upstreams sort first so every server gets probed.
+		let mut order: Vec<usize> = (0..n).collect();
+		order.sort_by_key(|i| self.upstreams[*i].rtt.load(Ordering::Relaxed));
+		order
+	    },
+	}
+    }
+
+    pub fn record_success(&self, i: usize, rtt: Duration) {
+	self.upstreams[i].update_rtt(rtt);
+    }
+
+    // A failed query counts as a sample of twice the timeout, pushing the
+    // upstream to the back of the lowest-latency order.
+    pub fn record_failure(&self, i: usize) {
+	self.upstreams[i].update_rtt(self.timeout * 2);
+    }
+
+    pub fn record_win(&self, i: usize) {
+	self.upstreams[i].wins.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn log_stats(&self) {
+	for u in &self.upstreams {
+	    info!("Upstream {}: rtt {:.1}ms, {} race wins", u.name,
+		  u.rtt.load(Ordering::Relaxed) as f64 / 1000.0, u.wins.load(Ordering::Relaxed));
+	}
+    }
+}
+
+fn random_index(n: usize) -> usize {
+    let mut buf = [0u8; 8];
+    getrandom::getrandom(&mut buf).expect("oops");
+    (u64::from_ne_bytes(buf) % n as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(strategy: Strategy) -> UpstreamPool {
+	UpstreamPool::new(vec![Upstream::parse("10.0.0.1", None, &[], None).unwrap(),
+			       Upstream::parse("10.0.0.2:5353", None, &[], None).unwrap(),
+			       Upstream::parse("[2620:fe::fe]:53", None, &[], None).unwrap()],
+			 strategy, Duration::from_secs(2))
+    }
+
+    #[test]
+    fn test_parse_addr() {
+	assert_eq!(parse_addr("9.9.9.9").unwrap(), "9.9.9.9:53".parse().unwrap());
+	assert_eq!(parse_addr("9.9.9.9:5353").unwrap(), "9.9.9.9:5353".parse().unwrap());
+	assert_eq!(parse_addr("2620:fe::fe").unwrap(), "[2620:fe::fe]:53".parse().unwrap());
+	assert!(parse_addr("dns.quad9.net").is_err());
+    }
+
+    #[test]
+    fn test_parse_upstream() {
+	let pin = [0u8; 32];
+	let quad9 = Some("9.9.9.9".parse().unwrap());
+	assert!(matches!(Upstream::parse("9.9.9.9", None, &[], None).unwrap().transport,
+			 Transport::Udp(addr) if addr == "9.9.9.9:53".parse().unwrap()));
+	match Upstream::parse("tls://[2606:4700::1111]", Some("one.one.one.one"), &[pin], None).unwrap().transport {
+	    Transport::Tls(c) => assert_eq!(format!("{:?}", c), "TlsClient([2606:4700::1111]:853)"),
+	    t => panic!("{:?}", t),
+	}
+	match Upstream::parse("https://dns.quad9.net/dns-query", None, &[], quad9).unwrap().transport {
+	    Transport::Https(c) => assert_eq!(format!("{:?}", c), "HttpsClient(https://dns.quad9.net/dns-query)"),
+	    t => panic!("{:?}", t),
+	}
+	assert!(Upstream::parse("https://9.9.9.9/dns-query", None, &[], None).is_ok());
+	assert!(Upstream::parse("9.9.9.9", None, &[pin], None).is_err());
+	assert!(Upstream::parse("9.9.9.9", None, &[], quad9).is_err());
+	assert!(Upstream::parse("tls://9.9.9.9/path", None, &[], None).is_err());
+	assert!(Upstream::parse("tls://dns.quad9.net", None, &[], None).is_err());
+	assert!(Upstream::parse("tls://9.9.9.9", None, &[], quad9).is_err());
+	assert!(Upstream::parse("https://dns.quad9.net/dns-query", None, &[], None).is_err());
+	assert!(Upstream::parse("https://9.9.9.9/dns-query", None, &[], quad9).is_err());
+	assert!(Upstream::parse("https://dns.quad9.net/dns-query", Some("x"), &[], quad9).is_err());
+	assert!(Upstream::parse("https:///dns-query", None, &[], quad9).is_err());
+    }
+
+    #[test]
+    fn test_round_robin() {
+	let p = pool(Strategy::RoundRobin);
+	assert_eq!(p.order(), vec![0, 1, 2]);
+	assert_eq!(p.order(), vec![1, 2, 0]);
+	assert_eq!(p.order(), vec![2, 0, 1]);
+	assert_eq!(p.order(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_random_covers_all() {
+	let p = pool(Strategy::Random);
+	for _ in 0..16 {
+	    let mut o = p.order();
+	    o.sort();
+	    assert_eq!(o, vec![0, 1, 2]);
+	}
+    }
+
+    #[test]
+    fn test_lowest_latency() {
+	let p = pool(Strategy::LowestLatency);
+	p.record_success(0, Duration::from_millis(30));
+	p.record_success(1, Duration::from_millis(10));
+	p.record_success(2, Duration::from_millis(20));
+	assert_eq!(p.order(), vec![1, 2, 0]);
+	p.record_failure(1);
+	assert_eq!(p.order(), vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn test_race_width() {
+	let mut p = pool(Strategy::Race);
+	assert_eq!(p.race_width(), 2);
+	p.set_race_width(3);
+	assert_eq!(p.race_width(), 3);
+	p.record_success(2, Duration::from_millis(5));
+	assert_eq!(p.order()[2], 2);
+	assert_eq!(pool(Strategy::RoundRobin).race_width(), 1);
+    }
+}
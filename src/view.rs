@@ -0,0 +1,375 @@
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::dns;
+use crate::message::{self, Message, MessageError};
+
+// Borrowing view of a wire format message. Nothing is decoded before it
+// is asked for, names and RDATA stay slices of the packet and walking
+// the records allocates nothing. Message::from builds the owned form on
+// top of it.
+
+const HEADER_LEN: usize = 12;
+// Longest name on the wire, RFC 1035 section 2.3.4.
+const MAX_NAME_LEN: usize = 255;
+
+fn read_u16(msg: &[u8], pos: usize) -> Result<u16, MessageError> {
+    msg.get(pos..pos + 2).map(BigEndian::read_u16).ok_or(MessageError::Truncated)
+}
+
+fn read_u32(msg: &[u8], pos: usize) -> Result<u32, MessageError> {
+    msg.get(pos..pos + 4).map(BigEndian::read_u32).ok_or(MessageError::Truncated)
+}
+
+// A possibly compressed name in a message.
+#[derive(Clone, Copy)]
+pub struct Name<'a> {
+    msg: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Name<'a> {
+    // Checks the name at pos and returns it with the offset right after
+    // it. Pointers must go before the labels they follow, so following
+    // them always ends.
+    pub fn parse(msg: &'a [u8], pos: usize) -> Result<(Name<'a>, usize), MessageError> {
+	let mut p = pos;
+	let mut start = pos;
+	let mut len = 1;
+	let mut end = None;
+	loop {
+	    let l = *msg.get(p).ok_or(MessageError::Truncated)? as usize;
+	    match l & 0xc0 {
+		0xc0 => {
+		    let target = (l & 0x3f) << 8 | *msg.get(p + 1).ok_or(MessageError::Truncated)? as usize;
+		    if target >= start {
+			return Err(MessageError::Malformed(format!("Bad compression pointer {}", target)));
+		    }
+		    end.get_or_insert(p + 2);
+		    p = target;
+		    start = target;
+		},
+		0 if l == 0 => break,
+		0 => {
+		    len += l + 1;
+		    if len > MAX_NAME_LEN {
+			return Err(MessageError::Malformed("name too long".to_owned()));
+		    }
+		    if p + 1 + l > msg.len() {
+			return Err(MessageError::Truncated);
+		    }
+		    p += 1 + l;
+		},
+		_ => return Err(MessageError::Malformed(format!("label type {:#x}", l))),
+	    }
+	}
+	Ok((Name{msg, pos}, end.unwrap_or(p + 1)))
+    }
+
+    pub fn labels(&self) -> Labels<'a> {
+	Labels{msg: self.msg, pos: self.pos}
+    }
+
+    // Compares with a name in the form Display gives, exactly or ignoring
+    // ASCII case, without decoding it.
+    pub fn matches(&self, name: &str, ignore_case: bool) -> bool {
+	let name = name.strip_suffix('.').unwrap_or(name);
+	let mut want = name.split('.').filter(|_| !name.is_empty());
+	let mut labels = self.labels();
+	loop {
+	    match (labels.next(), want.next()) {
+		(None, None) => return true,
+		(Some(l), Some(w)) if ignore_case && l.eq_ignore_ascii_case(w.as_bytes()) => (),
+		(Some(l), Some(w)) if l == w.as_bytes() => (),
+		_ => return false,
+	    }
+	}
+    }
+}
+
+// Absolute form, "example.com." or "." for the root.
+impl std::fmt::Display for Name<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+	let mut empty = true;
+	for l in self.labels() {
+	    write!(f, "{}.", String::from_utf8_lossy(l))?;
+	    empty = false;
+	}
+	if empty {
+	    write!(f, ".")?;
+	}
+	Ok(())
+    }
+}
+
+// The labels of a name checked by Name::parse, following pointers.
+pub struct Labels<'a> {
+    msg: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for Labels<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+	loop {
+	    let l = self.msg[self.pos] as usize;
+	    if l == 0 {
+		return None;
+	    }
+	    if l & 0xc0 == 0xc0 {
+		self.pos = (l & 0x3f) << 8 | self.msg[self.pos + 1] as usize;
+		continue;
+	    }
+	    let label = &self.msg[self.pos + 1..self.pos + 1 + l];
+	    self.pos += 1 + l;
+	    return Some(label);
+	}
+    }
+}
+
+pub struct QuestionView<'a> {
+    pub name: Name<'a>,
+    pub qtype: dns::RecordType,
+    pub class: dns::RecordClass,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Section {
+    Answer,
+    Authority,
+    Additional,
+}
+
+pub struct RecordView<'a> {
+    msg: &'a [u8],
+    pub section: Section,
+    pub name: Name<'a>,
+    pub rtype: dns::RecordType,
+    // The payload size for OPT.
+    pub class: dns::RecordClass,
+    pub ttl: u32,
+    pub rdata: &'a [u8],
+    // Where the RDATA starts, names in it may point before it.
+    pub offset: usize,
+}
+
+impl RecordView<'_> {
+    // Decodes the RDATA into its owned, typed form.
+    pub fn data(&self) -> Result<dns::ResourceData, MessageError> {
+	message::parse_rdata(self.msg, self.offset, self.rtype, self.rdata.len())
+    }
+}
+
+pub struct MessageView<'a> {
+    msg: &'a [u8],
+}
+
+impl<'a> MessageView<'a> {
+    pub fn new(msg: &'a [u8]) -> Result<MessageView<'a>, MessageError> {
+	if msg.len() < HEADER_LEN {
+	    return Err(MessageError::Truncated);
+	}
+	Ok(MessageView{msg})
+    }
+
+    fn count(&self, i: usize) -> u16 {
+	BigEndian::read_u16(&self.msg[4 + 2 * i..])
+    }
+
+    pub fn question_count(&self) -> u16 {
+	self.count(0)
+    }
+
+    // The header fields, as a message with empty sections.
+    pub fn header(&self) -> Message {
+	let flags = BigEndian::read_u16(&self.msg[2..]);
+	let bit = |n: u16| (flags >> n) as u8 & 1;
+	let mut m = Message::new();
+	m.id = BigEndian::read_u16(self.msg) as u32;
+	m.qr = bit(15);
+	m.opcode = (flags >> 11) as u32 & 0xf;
+	m.aa = bit(10);
+	m.tc = bit(9);
+	m.rd = bit(8);
+	m.ra = bit(7);
+	m.ad = bit(5);
+	m.cd = bit(4);
+	m.rcode = flags as u32 & 0xf;
+	m
+    }
+
+    pub fn questions(&self) -> Questions<'a> {
+	Questions{msg: self.msg, pos: HEADER_LEN, remaining: self.count(0)}
+    }
+
+    // The answer, authority and additional records in order, including
+    // OPT.
+    pub fn records(&self) -> Records<'a> {
+	let mut questions = self.questions();
+	let error = questions.by_ref().find_map(|q| q.err());
+	Records{
+	    msg: self.msg,
+	    pos: questions.pos,
+	    remaining: [self.count(1), self.count(2), self.count(3)],
+	    error,
+	}
+    }
+}
+
+pub struct Questions<'a> {
+    msg: &'a [u8],
+    pos: usize,
+    remaining: u16,
+}
+
+impl<'a> Questions<'a> {
+    fn parse(&mut self) -> Result<QuestionView<'a>, MessageError> {
+	let (name, pos) = Name::parse(self.msg, self.pos)?;
+	let qtype = dns::RecordType::try_from(read_u16(self.msg, pos)?)?;
+	let class = dns::RecordClass::try_from(read_u16(self.msg, pos + 2)?)?;
+	self.pos = pos + 4;
+	Ok(QuestionView{name, qtype, class})
+    }
+}
+
+// Stops after the first error.
+impl<'a> Iterator for Questions<'a> {
+    type Item = Result<QuestionView<'a>, MessageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+	if self.remaining == 0 {
+	    return None;
+	}
+	self.remaining -= 1;
+	let q = self.parse();
+	if q.is_err() {
+	    self.remaining = 0;
+	}
+	Some(q)
+    }
+}
+
+pub struct Records<'a> {
+    msg: &'a [u8],
+    pos: usize,
+    remaining: [u16; 3],
+    // From the questions, returned before any record.
+    error: Option<MessageError>,
+}
+
+impl<'a> Records<'a> {
+    fn parse(&mut self, section: Section) -> Result<RecordView<'a>, MessageError> {
+	let (name, pos) = Name::parse(self.msg, self.pos)?;
+	let rtype = dns::RecordType::try_from(read_u16(self.msg, pos)?)?;
+	let class = dns::RecordClass::try_from(read_u16(self.msg, pos + 2)?)?;
+	let ttl = read_u32(self.msg, pos + 4)?;
+	let rdlen = read_u16(self.msg, pos + 8)? as usize;
+	let offset = pos + 10;
+	let rdata = self.msg.get(offset..offset + rdlen).ok_or(MessageError::Truncated)?;
+	self.pos = offset + rdlen;
+	Ok(RecordView{msg: self.msg, section, name, rtype, class, ttl, rdata, offset})
+    }
+}
+
+// Stops after the first error.
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<RecordView<'a>, MessageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+	if let Some(e) = self.error.take() {
+	    self.remaining = [0; 3];
+	    return Some(Err(e));
+	}
+	let i = self.remaining.iter().position(|n| *n > 0)?;
+	self.remaining[i] -= 1;
+	let section = [Section::Answer, Section::Authority, Section::Additional][i];
+	let r = self.parse(section);
+	if r.is_err() {
+	    self.remaining = [0; 3];
+	}
+	Some(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn answer() -> Vec<u8> {
+	let mut m = Message::new();
+	m.id = 7;
+	m.qr = 1;
+	m.rd = 1;
+	m.questions.push(message::Question{
+	    name: "www.example.com.".to_owned(),
+	    qtype: dns::RecordType::A,
+	    class: dns::RecordClass::IN,
+	});
+	m.answers.push(dns::ResourceRecord{
+	    name: "www.example.com.".to_owned(),
+	    rtype: dns::RecordType::CNAME,
+	    class: dns::RecordClass::IN,
+	    ttl: 300,
+	    data: dns::ResourceData::CName("cdn.example.net.".to_owned()),
+	});
+	for i in 0..8 {
+	    m.answers.push(dns::ResourceRecord{
+		name: "cdn.example.net.".to_owned(),
+		rtype: dns::RecordType::A,
+		class: dns::RecordClass::IN,
+		ttl: 60,
+		data: dns::ResourceData::IPv4(Ipv4Addr::new(192, 0, 2, i)),
+	    });
+	}
+	m.edns = Some(message::Edns::new(1232));
+	m.into_bytes().unwrap()
+    }
+
+    #[test]
+    fn test_view() {
+	let data = answer();
+	let view = MessageView::new(&data).unwrap();
+	assert_eq!(view.header().id, 7);
+	let q = view.questions().next().unwrap().unwrap();
+	assert_eq!(q.name.labels().collect::<Vec<_>>(), vec![&b"www"[..], b"example", b"com"]);
+	assert!(q.name.matches("www.example.com.", false));
+	assert!(q.name.matches("WWW.example.com", true));
+	assert!(!q.name.matches("WWW.example.com.", false));
+	assert!(!q.name.matches("example.com.", true));
+	assert!(!q.name.matches("www.example.com.au.", true));
+	assert!(!q.name.matches(".", true));
+	assert!(Name::parse(&[0], 0).unwrap().0.matches(".", false));
+	let records: Vec<_> = view.records().collect::<Result<_, _>>().unwrap();
+	assert_eq!(records.len(), 10);
+	// The A records are owned by a pointer into the CNAME's RDATA.
+	assert_eq!(records[1].name.to_string(), "cdn.example.net.");
+	assert_eq!(records[1].rdata, &[192, 0, 2, 0]);
+	assert_eq!(records[9].section, Section::Additional);
+	assert!(matches!(records[9].rtype, dns::RecordType::OPT));
+	assert!(MessageView::new(&data[..11]).is_err());
+	assert!(matches!(view.records().last(), Some(Ok(_))));
+	assert!(matches!(MessageView::new(&data[..40]).unwrap().records().last(), Some(Err(MessageError::Truncated))));
+    }
+
+    #[test]
+    fn test_bad_names() {
+	let mut data = answer();
+	// Pointer from the question name forward into the answers.
+	data[12..14].copy_from_slice(&[0xc0, 40]);
+	assert!(matches!(Name::parse(&data, 12), Err(MessageError::Malformed(_))));
+	// Label types 01 and 10 are not in use.
+	data[12] = 0x40;
+	assert!(matches!(Name::parse(&data, 12), Err(MessageError::Malformed(_))));
+	// A pointer to the name's own labels.
+	let data = [1, b'a', 0xc0, 0];
+	assert!(matches!(Name::parse(&data, 0), Err(MessageError::Malformed(_))));
+	let mut long = Vec::new();
+	for _ in 0..128 {
+	    long.extend_from_slice(&[1, b'a']);
+	}
+	long.push(0);
+	assert!(matches!(Name::parse(&long, 0), Err(MessageError::Malformed(_))));
+	assert_eq!(Name::parse(&long[2..], 0).unwrap().1, 255);
+    }
+}